@@ -0,0 +1,109 @@
+use crate::calc::*;
+use crate::error::{LiqPoolError, Result};
+use crate::liq_pool::LiqPool;
+
+/// Builder of `LiqPool` taking fees in basis points instead of fractions
+/// of UNIT.
+#[derive(Clone, Debug)]
+pub struct LiqPoolBuilder {
+    max_fee_bps: u16,
+    min_fee_bps: u16,
+    liq_target: u64,
+    price: u64,
+}
+
+impl Default for LiqPoolBuilder {
+    fn default() -> Self {
+        LiqPoolBuilder {
+            max_fee_bps: 0,
+            min_fee_bps: 0,
+            liq_target: 0,
+            price: UNIT,
+        }
+    }
+}
+
+impl LiqPoolBuilder {
+    pub fn new() -> LiqPoolBuilder {
+        LiqPoolBuilder::default()
+    }
+
+    /// Fee charged when liquidity is drained completely, ex. 300 for 3%.
+    pub fn max_fee_bps(mut self, max_fee_bps: u16) -> LiqPoolBuilder {
+        self.max_fee_bps = max_fee_bps;
+        self
+    }
+
+    /// Fee charged when liquidity target is reached, ex. 30 for 0.3%.
+    pub fn min_fee_bps(mut self, min_fee_bps: u16) -> LiqPoolBuilder {
+        self.min_fee_bps = min_fee_bps;
+        self
+    }
+
+    pub fn liq_target(mut self, liq_target: u64) -> LiqPoolBuilder {
+        self.liq_target = liq_target;
+        self
+    }
+
+    /// Price of st_token in token. Defaults to 1.
+    pub fn price(mut self, price: u64) -> LiqPoolBuilder {
+        self.price = price;
+        self
+    }
+
+    /// Create the pool, validating configuration the same way as
+    /// `LiqPool::new`.
+    pub fn build(&self) -> Result<LiqPool> {
+        LiqPool::new(
+            bps_to_fee(self.max_fee_bps)?,
+            bps_to_fee(self.min_fee_bps)?,
+            self.liq_target,
+            self.price,
+        )
+    }
+}
+
+fn bps_to_fee(bps: u16) -> Result<u64> {
+    if bps as u64 > BPS_SCALE {
+        return Err(LiqPoolError::invalid_input(
+            "fee cannot be greater than 10000 basis points",
+        ));
+    }
+    Ok(bps_to_unit(bps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_building_pool() {
+        let liq_pool = LiqPoolBuilder::new()
+            .max_fee_bps(300)
+            .min_fee_bps(30)
+            .liq_target(100000 * UNIT)
+            .build()
+            .unwrap();
+        assert_eq!(liq_pool.max_fee(), 3 * UNIT / 100);
+        assert_eq!(liq_pool.min_fee(), 3 * UNIT / 1000);
+        assert_eq!(liq_pool.liq_target(), 100000 * UNIT);
+        assert_eq!(liq_pool.price(), UNIT);
+    }
+
+    #[test]
+    fn test_building_pool_with_too_big_fee() {
+        let builder = LiqPoolBuilder::new().liq_target(100000 * UNIT);
+        assert!(builder.clone().max_fee_bps(10000).build().is_ok());
+        assert!(matches!(
+            builder.max_fee_bps(10001).build(),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_building_pool_with_invalid_config() {
+        let builder = LiqPoolBuilder::new().max_fee_bps(300).min_fee_bps(30);
+        assert!(builder.build().is_err());
+        assert!(builder.liq_target(UNIT).min_fee_bps(301).build().is_err());
+    }
+}
@@ -1,25 +1,62 @@
+#[cfg(feature = "alloc")]
+use alloc::{format, string::String};
+
 use crate::error::{LiqPoolError, Result};
 
 /// How 1 token is represented in u64 number.
 /// Values less than UNIT are fractions. 1 is the smallest unit (ex. lamport in SOL).
 pub const UNIT: u64 = 1000000000;
 
+/// Max fee of Marinade liquidity pool, 3%.
+pub const DEFAULT_MAX_FEE: u64 = 3 * UNIT / 100;
+
+/// Min fee of Marinade liquidity pool, 0.3%.
+pub const DEFAULT_MIN_FEE: u64 = 3 * UNIT / 1000;
+
+/// Liquidity target used by default, 100000 token.
+pub const DEFAULT_LIQ_TARGET: u64 = 100000 * UNIT;
+
+/// How many basis points make the whole.
+pub const BPS_SCALE: u64 = 10000;
+
+/// Number of fractional digits of UNIT.
+#[cfg(feature = "alloc")]
+const UNIT_DECIMALS: usize = 9;
+
 /// Calculate amount * (nominator / denominator)
 pub fn propotion(amount: u64, nominator: u64, denominator: u64) -> Result<u64> {
     u64::try_from((amount as u128 * nominator as u128) / denominator as u128)
-        .map_err(|_| LiqPoolError::CalculationError)
+        .map_err(|_| LiqPoolError::Overflow)
 }
 
 pub fn value(amount: u64, price: u64) -> Result<u64> {
-    propotion(amount, price, UNIT)
+    value_in_unit(amount, price, UNIT)
+}
+
+/// Same as `value`, but price is a fraction of `unit` instead of UNIT, ex.
+/// 1000000 for tokens with 6 decimals.
+pub fn value_in_unit(amount: u64, price: u64, unit: u64) -> Result<u64> {
+    propotion(amount, price, unit)
+}
+
+/// Inverse of `value`: amount worth `value` at `price`, rounded down.
+pub fn amount_from_value(value: u64, price: u64) -> Result<u64> {
+    if price == 0 {
+        return Err(LiqPoolError::invalid_input("price cannot be zero"));
+    }
+    propotion(value, UNIT, price)
 }
 
 /// Calculate someone's share after adding `value` to pool with `total_value`
 /// of something and `total_share` of something
+///
+/// First mint prices a share at 1. When there is already some value without
+/// any shares, the first minter gets it too, so it is minted as well
+/// instead of leaving price of a share above 1.
 pub fn shares(value: u64, total_value: u64, total_shares: u64) -> Result<u64> {
     // first mint
     if total_shares == 0 {
-        Ok(value)
+        value.checked_add(total_value).ok_or(LiqPoolError::Overflow)
     } else {
         propotion(value, total_shares, total_value)
     }
@@ -27,6 +64,298 @@ pub fn shares(value: u64, total_value: u64, total_shares: u64) -> Result<u64> {
 
 /// Given amount and a fee represented as a fraction in u64, calculate
 /// amount with subtracted fee.
+///
+/// Fee is rounded down, in favor of the payer.
 pub fn apply_fee(amount: u64, fee: u64) -> Result<u64> {
-    Ok(amount - value(amount, fee)?)
+    apply_fee_in_unit(amount, fee, UNIT)
+}
+
+/// Same as `apply_fee`, but fee is a fraction of `unit` instead of UNIT.
+///
+/// Fee above 100% is rejected, so a misconfigured fee curve fails the swap
+/// instead of panicking.
+pub fn apply_fee_in_unit(amount: u64, fee: u64, unit: u64) -> Result<u64> {
+    if fee > unit {
+        return Err(LiqPoolError::invalid_input(
+            "fee cannot be greater than 100%",
+        ));
+    }
+    amount
+        .checked_sub(value_in_unit(amount, fee, unit)?)
+        .ok_or(LiqPoolError::CalculationError)
+}
+
+/// Same as `apply_fee`, but fee is rounded up and net amount down, so
+/// the receiver of the fee never loses to rounding.
+///
+/// Net amount and fee always add up to `amount`, the fee being
+/// `ceil(amount * fee / UNIT)`. Fee above 100% is rejected like by
+/// `apply_fee_in_unit`.
+pub fn apply_fee_round_up(amount: u64, fee: u64) -> Result<u64> {
+    if fee > UNIT {
+        return Err(LiqPoolError::invalid_input(
+            "fee cannot be greater than 100%",
+        ));
+    }
+    let fee_amount = (amount as u128 * fee as u128).div_ceil(UNIT as u128);
+    let fee_amount = u64::try_from(fee_amount).map_err(|_| LiqPoolError::Overflow)?;
+    amount
+        .checked_sub(fee_amount)
+        .ok_or(LiqPoolError::CalculationError)
+}
+
+/// Inverse of `apply_fee`: compute gross amount which is `net` after
+/// subtracting `fee`.
+///
+/// Gross amount is rounded down, which makes
+/// `apply_fee(net_to_gross(net, fee), fee) == net` hold exactly.
+pub fn net_to_gross(net: u64, fee: u64) -> Result<u64> {
+    if fee >= UNIT {
+        return Err(LiqPoolError::invalid_input(
+            "fee has to be smaller than 100%",
+        ));
+    }
+    u64::try_from(net as u128 * UNIT as u128 / (UNIT - fee) as u128)
+        .map_err(|_| LiqPoolError::Overflow)
+}
+
+/// Inverse of `apply_fee_round_up`, gross amount is rounded up.
+///
+/// Result is the smallest amount which is at least `net` after
+/// `apply_fee_round_up`, and `apply_fee_round_up(net_to_gross_round_up(net,
+/// fee), fee) == net` holds exactly.
+pub fn net_to_gross_round_up(net: u64, fee: u64) -> Result<u64> {
+    if fee >= UNIT {
+        return Err(LiqPoolError::invalid_input(
+            "fee has to be smaller than 100%",
+        ));
+    }
+    u64::try_from((net as u128 * UNIT as u128).div_ceil((UNIT - fee) as u128))
+        .map_err(|_| LiqPoolError::Overflow)
+}
+
+/// Convert basis points to fraction scaled by UNIT.
+pub fn bps_to_unit(bps: u16) -> u64 {
+    bps as u64 * UNIT / BPS_SCALE
+}
+
+/// Convert fraction scaled by UNIT to basis points, rounded to the nearest
+/// one.
+pub fn unit_to_bps(fraction: u64) -> u64 {
+    ((fraction as u128 * BPS_SCALE as u128 + UNIT as u128 / 2) / UNIT as u128) as u64
+}
+
+/// Token paid out for `st_token` swapped at `price` and charged `fee`, by
+/// the formula of Marinade documentation `st_token * price * (1 - fee)`
+/// computed in floating point, as a reference for the integer math.
+#[cfg(test)]
+pub(crate) fn expected_swap_out(st_token: u64, price: u64, fee: u64) -> f64 {
+    let unit = UNIT as f64;
+    st_token as f64 * (price as f64 / unit) * (1.0 - fee as f64 / unit)
+}
+
+/// Square root of non-negative `x` with Newton's method, `f64::sqrt` is not
+/// available in `core`.
+pub(crate) fn sqrt(x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    // Halving the exponent gives a guess within few percent of the root.
+    let mut root = f64::from_bits((x.to_bits() >> 1) + (1023 << 51));
+    for _ in 0..8 {
+        root = (root + x / root) / 2.0;
+    }
+    root
+}
+
+/// Round non-negative `x` up to integer.
+pub(crate) fn ceil(x: f64) -> u64 {
+    let truncated = x as u64;
+    if (truncated as f64) < x {
+        truncated + 1
+    } else {
+        truncated
+    }
+}
+
+/// Format amount scaled by UNIT as decimal number with all fractional
+/// digits, ex. 1500000000 -> "1.500000000".
+#[cfg(feature = "alloc")]
+pub fn format_unit(amount: u64) -> String {
+    format!(
+        "{}.{:0width$}",
+        amount / UNIT,
+        amount % UNIT,
+        width = UNIT_DECIMALS
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_six_decimal_unit() {
+        let unit = 1000000;
+        // 0.3% fee from 1001 USDC
+        assert_eq!(
+            apply_fee_in_unit(1001 * unit, 3 * unit / 1000, unit).unwrap(),
+            997997 * unit / 1000
+        );
+        assert_eq!(
+            value_in_unit(150 * unit, 2 * unit, unit).unwrap(),
+            300 * unit
+        );
+        assert_eq!(
+            apply_fee_in_unit(1001, 3 * UNIT / 1000, UNIT).unwrap(),
+            apply_fee(1001, 3 * UNIT / 1000).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_net_to_gross() {
+        for net in [0, 1, 997, 1001, 2991 * UNIT / 10, u64::MAX / 2] {
+            for fee in [0, 1, 3 * UNIT / 1000, 3 * UNIT / 100, UNIT / 2] {
+                let gross = net_to_gross(net, fee).unwrap();
+                assert_eq!(apply_fee(gross, fee).unwrap(), net);
+                let gross = net_to_gross_round_up(net, fee).unwrap();
+                assert_eq!(apply_fee_round_up(gross, fee).unwrap(), net);
+                if gross > 0 {
+                    assert!(apply_fee_round_up(gross - 1, fee).unwrap() < net);
+                }
+            }
+        }
+        assert_eq!(net_to_gross(998, 3 * UNIT / 1000).unwrap(), 1001);
+        assert_eq!(net_to_gross_round_up(998, 3 * UNIT / 1000).unwrap(), 1002);
+        assert!(matches!(
+            net_to_gross(1, UNIT),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(matches!(
+            net_to_gross(u64::MAX, UNIT / 2),
+            Err(LiqPoolError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_bps_conversions() {
+        assert_eq!(bps_to_unit(300), 3 * UNIT / 100);
+        assert_eq!(bps_to_unit(10000), UNIT);
+        for bps in [0, 1, 30, 300, 9999, 10000, u16::MAX] {
+            assert_eq!(unit_to_bps(bps_to_unit(bps)), bps as u64);
+        }
+        // 1.91514% rounds to 192 basis points
+        assert_eq!(unit_to_bps(191514 * UNIT / 10000000), 192);
+        assert_eq!(unit_to_bps(UNIT / 20000), 1);
+        assert_eq!(unit_to_bps(UNIT / 20000 - 1), 0);
+        assert_eq!(unit_to_bps(u64::MAX), 184467440737096);
+    }
+
+    #[test]
+    fn test_sqrt() {
+        assert_eq!(sqrt(0.0), 0.0);
+        assert_eq!(sqrt(-1.0), 0.0);
+        for x in [1e-9, 0.5, 2.0, 1e9, 3.3e28, 1e56] {
+            assert!((sqrt(x) - x.sqrt()).abs() <= x.sqrt() * 1e-15);
+        }
+        assert_eq!(ceil(2.0), 2);
+        assert_eq!(ceil(2.1), 3);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_format_unit() {
+        assert_eq!(format_unit(2991 * UNIT / 10), "299.100000000");
+        assert_eq!(format_unit(1), "0.000000001");
+        assert_eq!(format_unit(0), "0.000000000");
+        assert_eq!(format_unit(u64::MAX), "18446744073.709551615");
+    }
+
+    #[test]
+    fn test_apply_fee_rounding() {
+        // 0.3% of 1001 is 3.003
+        assert_eq!(apply_fee(1001, 3 * UNIT / 1000).unwrap(), 998);
+        assert_eq!(apply_fee_round_up(1001, 3 * UNIT / 1000).unwrap(), 997);
+        // Exact fee is not rounded at all.
+        assert_eq!(apply_fee_round_up(1000, 3 * UNIT / 1000).unwrap(), 997);
+        assert_eq!(apply_fee_round_up(1, 1).unwrap(), 0);
+        assert_eq!(apply_fee_round_up(1, 0).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_amount_from_value() {
+        for price in [1, UNIT / 3, UNIT, 2 * UNIT, 1000 * UNIT + 7] {
+            for amount in [0, 1, 997, 2991 * UNIT / 10, 1000000 * UNIT] {
+                let round_trip = amount_from_value(value(amount, price).unwrap(), price).unwrap();
+                // Value is rounded down by less than 1, which is worth less
+                // than UNIT / price of amount.
+                assert!(round_trip <= amount);
+                assert!(amount - round_trip <= UNIT.div_ceil(price));
+            }
+        }
+        assert_eq!(amount_from_value(300 * UNIT, 2 * UNIT).unwrap(), 150 * UNIT);
+        assert!(matches!(
+            amount_from_value(UNIT, 0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(matches!(
+            amount_from_value(u64::MAX, UNIT / 2),
+            Err(LiqPoolError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_first_mint_shares() {
+        assert_eq!(shares(500, 0, 0).unwrap(), 500);
+        assert_eq!(shares(500, 200, 0).unwrap(), 700);
+        assert!(matches!(
+            shares(u64::MAX, 1, 0),
+            Err(LiqPoolError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_propotion_overflow() {
+        assert!(matches!(
+            propotion(u64::MAX, 2, 1),
+            Err(LiqPoolError::Overflow)
+        ));
+        assert_eq!(propotion(u64::MAX, 2, 2).unwrap(), u64::MAX);
+        assert!(matches!(
+            value(u64::MAX, 2 * UNIT),
+            Err(LiqPoolError::Overflow)
+        ));
+        assert!(matches!(
+            shares(u64::MAX, 1, 2),
+            Err(LiqPoolError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_apply_fee_too_big_fee() {
+        assert!(matches!(
+            apply_fee(100, UNIT + 1),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(matches!(
+            apply_fee(u64::MAX, 2 * UNIT),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert_eq!(apply_fee(100, UNIT).unwrap(), 0);
+        assert_eq!(apply_fee(u64::MAX, UNIT).unwrap(), 0);
+        assert!(apply_fee_in_unit(100, 1000001, 1000000).is_err());
+    }
+
+    #[test]
+    fn test_apply_fee_round_up_too_big_fee() {
+        assert!(matches!(
+            apply_fee_round_up(100, UNIT + 1),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(matches!(
+            apply_fee_round_up(0, UNIT + 1),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert_eq!(apply_fee_round_up(100, UNIT).unwrap(), 0);
+    }
 }
@@ -1,13 +1,139 @@
-use thiserror::Error;
+use core::fmt;
 
-#[derive(Error, Debug)]
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+
+#[derive(Debug)]
 pub enum LiqPoolError {
-    #[error("Program tried to do erroneous calculation")]
     CalculationError,
-    #[error("A logically impossible input value: {0}")]
+    Overflow,
+    /// Without `alloc` feature the description is a static string.
+    #[cfg(feature = "alloc")]
     InvalidInputData(String),
-    #[error("Liquidity of the pool was to small to execute operation")]
-    InsufficientLiquidity,
+    #[cfg(not(feature = "alloc"))]
+    InvalidInputData(&'static str),
+    InsufficientLiquidity {
+        requested: u64,
+        available: u64,
+    },
+    SlippageExceeded {
+        expected: u64,
+        actual: u64,
+    },
+    DuplicateOperation(u64),
+    CapExceeded,
+    PoolNotInitialized,
+}
+
+impl LiqPoolError {
+    /// Create `InvalidInputData` with static `description`, which is owned
+    /// only with `alloc` feature.
+    // Without alloc the conversion is identity.
+    #[allow(clippy::useless_conversion)]
+    pub(crate) fn invalid_input(description: &'static str) -> LiqPoolError {
+        LiqPoolError::InvalidInputData(description.into())
+    }
 }
 
-pub type Result<T> = std::result::Result<T, LiqPoolError>;
+impl fmt::Display for LiqPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LiqPoolError::CalculationError => {
+                write!(f, "Program tried to do erroneous calculation")
+            }
+            LiqPoolError::Overflow => write!(f, "Result of calculation does not fit in u64"),
+            LiqPoolError::InvalidInputData(msg) => {
+                write!(f, "A logically impossible input value: {msg}")
+            }
+            LiqPoolError::InsufficientLiquidity {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Liquidity of the pool was to small to execute operation: requested {requested}, available {available}"
+            ),
+            LiqPoolError::SlippageExceeded { expected, actual } => write!(
+                f,
+                "Swap would pay out {actual}, less than expected minimum {expected}"
+            ),
+            LiqPoolError::DuplicateOperation(nonce) => {
+                write!(f, "Operation with nonce {nonce} has already been applied")
+            }
+            LiqPoolError::CapExceeded => {
+                write!(f, "Deposit would push total value of the pool over its cap")
+            }
+            LiqPoolError::PoolNotInitialized => {
+                write!(
+                    f,
+                    "Pool has no liquidity, it has to be seeded with token first"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LiqPoolError {}
+
+/// Arithmetic errors map to `ArithmeticOverflow`, the rest to custom
+/// error codes, one per variant.
+#[cfg(feature = "solana")]
+impl From<LiqPoolError> for solana_program::program_error::ProgramError {
+    fn from(error: LiqPoolError) -> Self {
+        use solana_program::program_error::ProgramError;
+        match error {
+            LiqPoolError::CalculationError | LiqPoolError::Overflow => {
+                ProgramError::ArithmeticOverflow
+            }
+            LiqPoolError::InvalidInputData(_) => ProgramError::Custom(0),
+            LiqPoolError::InsufficientLiquidity { .. } => ProgramError::Custom(1),
+            LiqPoolError::SlippageExceeded { .. } => ProgramError::Custom(2),
+            LiqPoolError::DuplicateOperation(_) => ProgramError::Custom(3),
+            LiqPoolError::CapExceeded => ProgramError::Custom(4),
+            LiqPoolError::PoolNotInitialized => ProgramError::Custom(5),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, LiqPoolError>;
+
+#[cfg(all(test, feature = "solana"))]
+mod tests {
+    use super::*;
+    use solana_program::program_error::ProgramError;
+
+    #[test]
+    fn test_program_error_conversion() {
+        let errors = [
+            (
+                LiqPoolError::CalculationError,
+                ProgramError::ArithmeticOverflow,
+            ),
+            (LiqPoolError::Overflow, ProgramError::ArithmeticOverflow),
+            (
+                LiqPoolError::InvalidInputData("fee".into()),
+                ProgramError::Custom(0),
+            ),
+            (
+                LiqPoolError::InsufficientLiquidity {
+                    requested: 2,
+                    available: 1,
+                },
+                ProgramError::Custom(1),
+            ),
+            (
+                LiqPoolError::SlippageExceeded {
+                    expected: 2,
+                    actual: 1,
+                },
+                ProgramError::Custom(2),
+            ),
+            (LiqPoolError::DuplicateOperation(7), ProgramError::Custom(3)),
+            (LiqPoolError::CapExceeded, ProgramError::Custom(4)),
+            (LiqPoolError::PoolNotInitialized, ProgramError::Custom(5)),
+        ];
+        for (error, program_error) in errors {
+            assert_eq!(ProgramError::from(error), program_error);
+        }
+    }
+}
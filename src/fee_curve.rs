@@ -0,0 +1,65 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LiqPoolError, Result};
+
+/// Curve deciding swap fee based on liquidity left in the pool after swap.
+pub trait FeeCurve {
+    /// Compute fee, as fraction of UNIT, for a swap leaving `liq_after`
+    /// token in the pool.
+    fn fee(&self, liq_after: u64, liq_target: u64, min_fee: u64, max_fee: u64) -> Result<u64>;
+}
+
+/// Fee falling linearly from max_fee for drained pool to min_fee when
+/// liquidity target is reached, as described in Marinade documentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LinearFee;
+
+impl FeeCurve for LinearFee {
+    fn fee(&self, liq_after: u64, liq_target: u64, min_fee: u64, max_fee: u64) -> Result<u64> {
+        if liq_after >= liq_target {
+            Ok(min_fee)
+        } else {
+            // Whole interpolation is done in u128, so the line is rounded
+            // only once.
+            let fee_range = (max_fee - min_fee) as u128;
+            let fee = max_fee as u128 - fee_range * liq_after as u128 / liq_target as u128;
+            u64::try_from(fee).map_err(|_| LiqPoolError::Overflow)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calc::UNIT;
+
+    fn assert_non_increasing(liq_target: u64, min_fee: u64, max_fee: u64, step: u64) {
+        let mut last_fee = max_fee;
+        let sweep = (0..liq_target).step_by(step as usize).chain([liq_target]);
+        for liq_after in sweep {
+            let fee = LinearFee
+                .fee(liq_after, liq_target, min_fee, max_fee)
+                .unwrap();
+            assert!(fee <= last_fee, "fee rises at liquidity {liq_after}");
+            assert!((min_fee..=max_fee).contains(&fee));
+            last_fee = fee;
+        }
+        assert_eq!(last_fee, min_fee);
+    }
+
+    #[test]
+    fn test_linear_fee_is_monotonic() {
+        // Range of fees much smaller than target.
+        assert_non_increasing(1000, 3 * UNIT / 1000, 3 * UNIT / 1000 + 3, 1);
+        assert_non_increasing(100000 * UNIT, 3 * UNIT / 1000, 3 * UNIT / 100, 1000000007);
+        assert_non_increasing(u64::MAX, 0, UNIT, u64::MAX / 1000);
+        assert_eq!(
+            LinearFee
+                .fee(50000 * UNIT, 100000 * UNIT, 3 * UNIT / 1000, 3 * UNIT / 100)
+                .unwrap(),
+            165 * UNIT / 10000
+        );
+    }
+}
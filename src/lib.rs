@@ -2,9 +2,27 @@
 //! linear swap fee as described in [`Marinade documentation`].
 //!
 //! [`Marinade documentation`]: https://docs.marinade.finance/marinade-protocol/system-overview/unstake-liquidity-pool
+//!
+//! The crate is `no_std` when default `std` feature is disabled. Nonce
+//! tracking, decimal formatting and parameter inference additionally need
+//! `alloc` feature.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
-mod calc;
+pub mod builder;
+pub mod calc;
 pub mod error;
+pub mod fee_curve;
 pub mod liq_pool;
 
-pub use crate::liq_pool::LiqPool;
+pub use crate::builder::LiqPoolBuilder;
+pub use crate::fee_curve::{FeeCurve, LinearFee};
+#[cfg(feature = "alloc")]
+pub use crate::liq_pool::BatchResult;
+pub use crate::liq_pool::{
+    parity_swap, EpochResult, LiqPool, Op, PartialSwap, PoolCheckpoint, PoolEvent, RelativeStats,
+    SwapCostSummary, SwapReceipt,
+};
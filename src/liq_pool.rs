@@ -1,8 +1,137 @@
+#[cfg(feature = "alloc")]
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+#[cfg(feature = "alloc")]
+use core::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 use crate::calc::*;
 use crate::error::{LiqPoolError, Result};
+use crate::fee_curve::{FeeCurve, LinearFee};
+
+/// How many deposit levels are tried when searching for the best one.
+const DEPOSIT_SEARCH_STEPS: u64 = 100;
+
+/// How many recently applied nonces pool remembers by default.
+#[cfg(feature = "alloc")]
+const DEFAULT_NONCE_WINDOW: usize = 16;
+
+/// Single operation that can be applied to liquidity pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    AddLiquidity(u64),
+    RemoveLiquidity(u64),
+    Swap(u64),
+}
+
+/// Record of a state transition done by a mutating operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolEvent {
+    AddLiquidity {
+        token_in: u64,
+        lp_token_minted: u64,
+    },
+    RemoveLiquidity {
+        lp_token_burned: u64,
+        token_out: u64,
+        st_token_out: u64,
+    },
+    /// Fee is token value of st_token_in that stayed in the pool.
+    Swap {
+        st_token_in: u64,
+        token_out: u64,
+        fee: u64,
+    },
+}
+
+/// Outcome of a batch of swaps, see `LiqPool::process_swaps`.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchResult {
+    /// Token paid out by each successful swap.
+    pub outputs: Vec<u64>,
+    pub total_token_out: u64,
+    /// Token value of swapped st_token withheld as fees.
+    pub total_fee: u64,
+    /// Index of the swap which failed and stopped the batch.
+    pub failed_at: Option<usize>,
+}
+
+/// Change of pool value during an epoch, split by its source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EpochResult {
+    /// Value the pool gained from fees of swaps in the epoch.
+    pub fee_value_gained: u64,
+    /// Value the pool gained (or lost) from change of st_token price.
+    pub price_value_gained: i128,
+}
+
+/// Statistics of a pool relative to reference pool, as fractions of UNIT.
+/// Ratio is `None` when corresponding value of reference pool is zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RelativeStats {
+    pub total_value_ratio: Option<u64>,
+    pub lp_supply_ratio: Option<u64>,
+    pub utilization_ratio: Option<u64>,
+}
+
+/// Outcome of a swap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapReceipt {
+    pub token_out: u64,
+    /// Fee charged, as fraction scaled by UNIT.
+    pub fee_rate: u64,
+    /// Token value of swapped st_token withheld by the pool.
+    pub fee_amount: u64,
+}
 
-/// Mathematical model of unstake liquidity pool with linear swap fee.
-pub struct LiqPool {
+/// Outcome of a swap limited by liquidity of the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartialSwap {
+    pub st_token_consumed: u64,
+    pub token_out: u64,
+    pub st_token_remaining: u64,
+}
+
+/// Costs of a swap, as shown on a receipt.
+///
+/// Components always reconcile:
+/// `fair_value - token_out == fee_token + rounding_loss`, which is exactly
+/// the amount the swap adds to collected fees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapCostSummary {
+    /// Value of swapped st_token at current price, rounded down as by the
+    /// swap.
+    pub fair_value: u64,
+    /// Token received by swapper.
+    pub token_out: u64,
+    /// Fee collected by the pool at the charged rate, rounded down.
+    pub fee_token: u64,
+    /// Token swapper loses because the swap rounds the fee up to whole
+    /// units, at most one.
+    pub rounding_loss: u64,
+    /// Token received per one st_token, as fraction of UNIT.
+    pub effective_rate: u64,
+    /// Fee as basis points of fair value.
+    pub fee_bps: u16,
+}
+
+/// Mathematical model of unstake liquidity pool with swap fee decided by
+/// `FeeCurve`, linear by default.
+///
+/// Deserialized pool is validated the same way as by `from_state`, also
+/// checking its nonce history and lp principal.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        try_from = "LiqPoolState<F>",
+        bound(deserialize = "F: FeeCurve + Clone + Deserialize<'de>")
+    )
+)]
+pub struct LiqPool<F = LinearFee> {
     max_fee: u64,
     min_fee: u64,
     liq_target: u64,
@@ -11,14 +140,295 @@ pub struct LiqPool {
     token: u64,
     st_token: u64,
     lp_token_supply: u64,
+
+    #[cfg(feature = "alloc")]
+    seen_nonces: VecDeque<u64>,
+    #[cfg(feature = "alloc")]
+    nonce_window: usize,
+
+    touched_max_fee: bool,
+    collected_fees: u64,
+    lp_principal: u64,
+    swap_count: u64,
+    total_st_token_volume: u64,
+    max_total_value: Option<u64>,
+    current_epoch: u64,
+
+    fee_curve: F,
+}
+
+/// Unvalidated fields of `LiqPool`, as deserialized.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct LiqPoolState<F> {
+    max_fee: u64,
+    min_fee: u64,
+    liq_target: u64,
+    price: u64,
+    token: u64,
+    st_token: u64,
+    lp_token_supply: u64,
+    seen_nonces: VecDeque<u64>,
+    nonce_window: usize,
+    touched_max_fee: bool,
+    collected_fees: u64,
+    lp_principal: u64,
+    swap_count: u64,
+    total_st_token_volume: u64,
+    max_total_value: Option<u64>,
+    current_epoch: u64,
+    fee_curve: F,
+}
+
+#[cfg(feature = "serde")]
+impl<F: FeeCurve + Clone> TryFrom<LiqPoolState<F>> for LiqPool<F> {
+    type Error = LiqPoolError;
+
+    fn try_from(state: LiqPoolState<F>) -> Result<Self> {
+        check_reserves(state.token, state.st_token, state.lp_token_supply)?;
+        if state.seen_nonces.len() > state.nonce_window {
+            return Err(LiqPoolError::invalid_input(
+                "more seen nonces than nonce window",
+            ));
+        }
+        // Principal is paid out together with the last lp token.
+        if state.lp_token_supply == 0 && state.lp_principal > 0 {
+            return Err(LiqPoolError::invalid_input(
+                "empty pool cannot have lp principal",
+            ));
+        }
+        let liq_pool = LiqPool::with_fee_curve(
+            state.max_fee,
+            state.min_fee,
+            state.liq_target,
+            state.price,
+            state.fee_curve,
+        )?;
+        Ok(LiqPool {
+            token: state.token,
+            st_token: state.st_token,
+            lp_token_supply: state.lp_token_supply,
+            seen_nonces: state.seen_nonces,
+            nonce_window: state.nonce_window,
+            touched_max_fee: state.touched_max_fee,
+            collected_fees: state.collected_fees,
+            lp_principal: state.lp_principal,
+            swap_count: state.swap_count,
+            total_st_token_volume: state.total_st_token_volume,
+            max_total_value: state.max_total_value,
+            current_epoch: state.current_epoch,
+            ..liq_pool
+        })
+    }
+}
+
+/// Empty pool with `DEFAULT_MAX_FEE`, `DEFAULT_MIN_FEE`,
+/// `DEFAULT_LIQ_TARGET` and st_token price of 1.
+impl Default for LiqPool {
+    fn default() -> Self {
+        LiqPool::new(DEFAULT_MAX_FEE, DEFAULT_MIN_FEE, DEFAULT_LIQ_TARGET, UNIT)
+            .expect("default parameters are valid")
+    }
+}
+
+/// Saved state of a pool, which can be only restored into it.
+#[derive(Clone, Debug)]
+pub struct PoolCheckpoint<F = LinearFee> {
+    state: LiqPool<F>,
 }
 
 impl LiqPool {
-    pub fn new(max_fee: u64, min_fee: u64, liq_target: u64, price: u64) -> LiqPool {
+    /// Create empty pool, rejecting nonsensical fee configuration and zero
+    /// liquidity target.
+    pub fn new(max_fee: u64, min_fee: u64, liq_target: u64, price: u64) -> Result<LiqPool> {
+        LiqPool::with_fee_curve(max_fee, min_fee, liq_target, price, LinearFee)
+    }
+
+    /// Create pool the same way as `new` and put `initial_token` into it,
+    /// returning the pool with lp tokens minted for the deposit.
+    pub fn new_funded(
+        max_fee: u64,
+        min_fee: u64,
+        liq_target: u64,
+        price: u64,
+        initial_token: u64,
+    ) -> Result<(LiqPool, u64)> {
+        let mut liq_pool = LiqPool::new(max_fee, min_fee, liq_target, price)?;
+        let lp_token_amount = liq_pool.add_liquidity(initial_token)?;
+        Ok((liq_pool, lp_token_amount))
+    }
+
+    /// Rebuild pool from its configuration and reserves, e.g. to mirror
+    /// a pool account read from chain.
+    ///
+    /// On top of validation done by `new`, rejects reserves that could not
+    /// have been reached: lp tokens minted for empty pool, or reserves
+    /// without any lp tokens minted.
+    pub fn from_state(
+        max_fee: u64,
+        min_fee: u64,
+        liq_target: u64,
+        price: u64,
+        token: u64,
+        st_token: u64,
+        lp_token_supply: u64,
+    ) -> Result<LiqPool> {
+        check_reserves(token, st_token, lp_token_supply)?;
+        let mut liq_pool = LiqPool::new(max_fee, min_fee, liq_target, price)?;
+        liq_pool.token = token;
+        liq_pool.st_token = st_token;
+        liq_pool.lp_token_supply = lp_token_supply;
+        // History of deposits is unknown, so nothing is left to withdraw as
+        // fees.
+        liq_pool.lp_principal = liq_pool.total_value();
+        Ok(liq_pool)
+    }
+
+    /// Same as `from_state`, with st_token price of 1 until it is updated
+    /// with `update_price`.
+    pub fn with_reserves(
+        max_fee: u64,
+        min_fee: u64,
+        liq_target: u64,
+        token: u64,
+        st_token: u64,
+        lp_token_supply: u64,
+    ) -> Result<LiqPool> {
+        LiqPool::from_state(
+            max_fee,
+            min_fee,
+            liq_target,
+            UNIT,
+            token,
+            st_token,
+            lp_token_supply,
+        )
+    }
+
+    /// Reconstruct `(min_fee, max_fee, liq_target)` of a pool from observed
+    /// swaps.
+    ///
+    /// Each observation is `(st_token_in, token_out, token_reserve)` where
+    /// token_reserve is token in the pool just before the swap, and
+    /// st_token_in is valued at `price`. Observations have to span both fee
+    /// regimes: at least one swap charged min_fee and at least two swaps
+    /// with different liquidity below target, from which the slope of the
+    /// fee is fitted.
+    #[cfg(feature = "alloc")]
+    pub fn infer_params(price: u64, observations: &[(u64, u64, u64)]) -> Result<(u64, u64, u64)> {
+        let invalid = LiqPoolError::invalid_input;
+
+        // (liquidity after swap, fee) for every observation
+        let mut points = Vec::with_capacity(observations.len());
+        for &(st_token_in, token_out, token_reserve) in observations {
+            let token_amount = value(st_token_in, price)?;
+            if token_amount == 0 || token_out > token_amount || token_amount > token_reserve {
+                return Err(invalid("observation is not a valid swap"));
+            }
+            let fee = propotion(token_amount - token_out, UNIT, token_amount)?;
+            points.push((token_reserve - token_amount, fee));
+        }
+
+        let min_fee = points
+            .iter()
+            .map(|&(_, fee)| fee)
+            .min()
+            .ok_or_else(|| invalid("no observations"))?;
+        let sloped = points.iter().filter(|&&(_, fee)| fee > min_fee);
+        let (l1, f1) = *sloped
+            .clone()
+            .min_by_key(|&&(liq_after, _)| liq_after)
+            .ok_or_else(|| invalid("no observations below liquidity target"))?;
+        let (l2, f2) = *sloped.max_by_key(|&&(liq_after, _)| liq_after).unwrap();
+        if l1 == l2 || f1 <= f2 {
+            return Err(invalid("observations do not determine slope of the fee"));
+        }
+
+        // Line through (l1, f1) and (l2, f2) crosses max_fee at zero
+        // liquidity and min_fee at liq_target.
+        let max_fee = f1 + propotion(f1 - f2, l1, l2 - l1)?;
+        let liq_target = l2 + propotion(f2 - min_fee, l2 - l1, f1 - f2)?;
+        if max_fee > UNIT {
+            return Err(invalid("inferred max fee is greater than 100%"));
+        }
+        for &(liq_after, fee) in &points {
+            if (fee == min_fee) != (liq_after >= liq_target) {
+                return Err(invalid("observations are inconsistent with linear fee"));
+            }
+        }
+        Ok((min_fee, max_fee, liq_target))
+    }
+
+    /// Compute total value the pool has to be seeded with, so that each of
+    /// `lp_supply` lp tokens is worth `target_price` token.
+    pub fn seed_reserves_for_price(target_price: u64, lp_supply: u64) -> Result<u64> {
+        value(lp_supply, target_price)
+    }
+
+    /// Compute the smallest liq_target for which swap of `peak_swap` token
+    /// value from a pool holding exactly liq_target pays at most
+    /// `fee_ceiling`.
+    ///
+    /// With token == liq_target == T, fee is
+    /// `max_fee - (max_fee - min_fee) * (T - peak_swap) / T`, so
+    /// T >= peak_swap * (max_fee - min_fee) / (fee_ceiling - min_fee).
+    pub fn target_for_peak_swap(
+        peak_swap: u64,
+        fee_ceiling: u64,
+        max_fee: u64,
+        min_fee: u64,
+    ) -> Result<u64> {
         if max_fee < min_fee {
-            panic!("LiqPool: Max fee cannot be smaller than min fee");
+            return Err(LiqPoolError::invalid_input(
+                "max fee cannot be smaller than min fee",
+            ));
+        }
+        if fee_ceiling >= max_fee {
+            return Ok(peak_swap);
+        }
+        if fee_ceiling <= min_fee {
+            return Err(LiqPoolError::invalid_input(
+                "fee ceiling has to be greater than min fee",
+            ));
+        }
+        let fee_range = (max_fee - min_fee) as u128;
+        let target = (peak_swap as u128 * fee_range).div_ceil((fee_ceiling - min_fee) as u128);
+        u64::try_from(target).map_err(|_| LiqPoolError::Overflow)
+    }
+
+    /// Compute the largest max_fee for which swap of `volume` token value
+    /// yields at least `min_output` token, even when charged max_fee.
+    ///
+    /// Swap pays `ceil(volume * fee / UNIT)`, which must not exceed
+    /// `volume - min_output`.
+    pub fn max_fee_for_output_guarantee(volume: u64, min_output: u64) -> Result<u64> {
+        if volume == 0 || min_output > volume {
+            return Err(LiqPoolError::invalid_input(
+                "guaranteed output has to be positive volume at most",
+            ));
+        }
+        propotion(volume - min_output, UNIT, volume)
+    }
+}
+
+impl<F: FeeCurve + Clone> LiqPool<F> {
+    /// Create empty pool charging fees according to `fee_curve`, rejecting
+    /// nonsensical fee configuration and zero liquidity target.
+    pub fn with_fee_curve(
+        max_fee: u64,
+        min_fee: u64,
+        liq_target: u64,
+        price: u64,
+        fee_curve: F,
+    ) -> Result<LiqPool<F>> {
+        check_fees(max_fee, min_fee)?;
+        check_price(price)?;
+        if liq_target == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "liquidity target cannot be zero",
+            ));
         }
-        LiqPool {
+        Ok(LiqPool {
             max_fee,
             min_fee,
             liq_target,
@@ -26,7 +436,92 @@ impl LiqPool {
             token: 0,
             st_token: 0,
             lp_token_supply: 0,
+            #[cfg(feature = "alloc")]
+            seen_nonces: VecDeque::new(),
+            #[cfg(feature = "alloc")]
+            nonce_window: DEFAULT_NONCE_WINDOW,
+            touched_max_fee: false,
+            collected_fees: 0,
+            lp_principal: 0,
+            swap_count: 0,
+            total_st_token_volume: 0,
+            max_total_value: None,
+            current_epoch: 0,
+            fee_curve,
+        })
+    }
+
+    /// Amount of token in the pool.
+    pub fn token_reserve(&self) -> u64 {
+        self.token
+    }
+
+    /// Amount of st_token in the pool.
+    pub fn st_token_reserve(&self) -> u64 {
+        self.st_token
+    }
+
+    /// Amount of lp tokens minted.
+    pub fn lp_token_supply(&self) -> u64 {
+        self.lp_token_supply
+    }
+
+    /// Fee charged when liquidity is drained completely.
+    pub fn max_fee(&self) -> u64 {
+        self.max_fee
+    }
+
+    /// Fee charged when liquidity after swap stays above liq_target.
+    pub fn min_fee(&self) -> u64 {
+        self.min_fee
+    }
+
+    /// Liquidity above which swaps are charged min_fee.
+    pub fn liq_target(&self) -> u64 {
+        self.liq_target
+    }
+
+    /// Price of st_token in token.
+    pub fn price(&self) -> u64 {
+        self.price
+    }
+
+    /// Set price of st_token in token, ex. after staking rewards are paid.
+    ///
+    /// Price of zero is rejected, like by `new`.
+    pub fn update_price(&mut self, price: u64) -> Result<()> {
+        check_price(price)?;
+        self.price = price;
+        Ok(())
+    }
+
+    /// Cap on total value of the pool deposits cannot exceed, if any.
+    pub fn max_total_value(&self) -> Option<u64> {
+        self.max_total_value
+    }
+
+    /// Set cap on total value of the pool, `None` removes it.
+    ///
+    /// Only deposits are checked against the cap, swaps and price updates
+    /// may still move total value past it.
+    pub fn set_max_total_value(&mut self, max_total_value: Option<u64>) {
+        self.max_total_value = max_total_value;
+    }
+
+    /// Total value locked in the pool in token, with st_token valued at
+    /// current price. Saturates at `u64::MAX`.
+    pub fn total_value(&self) -> u64 {
+        self.pool_value().unwrap_or(u64::MAX)
+    }
+
+    /// Value of one lp token in token, as fraction of UNIT.
+    ///
+    /// Before first deposit lp token is worth exactly 1 token.
+    pub fn lp_token_price(&self) -> Result<u64> {
+        if self.lp_token_supply == 0 {
+            return Ok(UNIT);
         }
+        propotion(self.pool_value()?, UNIT, self.lp_token_supply)
     }
 
     /// Simulate putting tokens into liquidity pool.
@@ -35,11 +530,82 @@ impl LiqPool {
     /// depends on ratio between total liquidity pool value (token + st_token)
     /// and lp_token_supply.
     pub fn add_liquidity(&mut self, token_amount: u64) -> Result<u64> {
-        let st_token_value = value(self.st_token, self.price)?;
-        let total_liq_pool_value = st_token_value + self.token;
-        let lp_token_to_mint = shares(token_amount, total_liq_pool_value, self.lp_token_supply)?;
-        self.token += token_amount;
-        self.lp_token_supply += lp_token_to_mint;
+        self.deposit(token_amount, 0)
+    }
+
+    /// Same as `add_liquidity`, but also returns share of the pool owned by
+    /// the minted lp tokens, as fraction of UNIT.
+    pub fn add_liquidity_with_share(&mut self, token_amount: u64) -> Result<(u64, u64)> {
+        let lp_token_amount = self.add_liquidity(token_amount)?;
+        let share = propotion(lp_token_amount, UNIT, self.lp_token_supply)?;
+        Ok((lp_token_amount, share))
+    }
+
+    /// Put as much of `token_amount` into the pool as its cap allows.
+    ///
+    /// Returns token amount accepted and lp tokens minted for it.
+    pub fn add_liquidity_up_to_cap(&mut self, token_amount: u64) -> Result<(u64, u64)> {
+        let accepted = match self.max_total_value {
+            Some(max_total_value) => {
+                token_amount.min(max_total_value.saturating_sub(self.pool_value()?))
+            }
+            None => token_amount,
+        };
+        if accepted == 0 {
+            return Ok((0, 0));
+        }
+        Ok((accepted, self.add_liquidity(accepted)?))
+    }
+
+    /// Simulate putting st_token into liquidity pool.
+    ///
+    /// Works like `add_liquidity`, with deposit valued at current price of
+    /// st_token.
+    /// Pool has to be seeded with token first, otherwise it fails with
+    /// `PoolNotInitialized`, as do swaps and removing liquidity.
+    pub fn add_liquidity_st(&mut self, st_token_amount: u64) -> Result<u64> {
+        self.check_initialized()?;
+        self.deposit(0, st_token_amount)
+    }
+
+    /// Simulate putting both token and st_token into liquidity pool.
+    ///
+    /// Lp tokens are minted for the combined value of the deposit, with
+    /// st_token valued at current price. Empty pool can be seeded this way
+    /// as long as some token is deposited.
+    pub fn add_liquidity_both(&mut self, token_amount: u64, st_token_amount: u64) -> Result<u64> {
+        if token_amount == 0 {
+            self.check_initialized()?;
+        }
+        self.deposit(token_amount, st_token_amount)
+    }
+
+    /// Put token and st_token into the pool, minting lp tokens according to
+    /// value of the deposit.
+    fn deposit(&mut self, token_amount: u64, st_token_amount: u64) -> Result<u64> {
+        if token_amount == 0 && st_token_amount == 0 {
+            return Err(LiqPoolError::invalid_input("cannot add zero liquidity"));
+        }
+        let total_liq_pool_value = self.pool_value()?;
+        let deposit_value = value(st_token_amount, self.price)?
+            .checked_add(token_amount)
+            .ok_or(LiqPoolError::Overflow)?;
+        if let Some(max_total_value) = self.max_total_value {
+            if total_liq_pool_value.saturating_add(deposit_value) > max_total_value {
+                return Err(LiqPoolError::CapExceeded);
+            }
+        }
+        let lp_token_to_mint = shares(deposit_value, total_liq_pool_value, self.lp_token_supply)?;
+        let add = |a: u64, b: u64| a.checked_add(b).ok_or(LiqPoolError::Overflow);
+        let token = add(self.token, token_amount)?;
+        let st_token = add(self.st_token, st_token_amount)?;
+        let lp_token_supply = add(self.lp_token_supply, lp_token_to_mint)?;
+        let lp_principal = add(self.lp_principal, deposit_value)?;
+        self.token = token;
+        self.st_token = st_token;
+        self.lp_token_supply = lp_token_supply;
+        self.lp_principal = lp_principal;
+        self.assert_invariants();
         Ok(lp_token_to_mint)
     }
 
@@ -47,145 +613,3606 @@ impl LiqPool {
     ///
     /// Caller gets token and st_token in propotion to their presence in liquidity pool.
     pub fn remove_liquidity(&mut self, lp_token_amount: u64) -> Result<(u64, u64)> {
-        if lp_token_amount > self.lp_token_supply {
-            return Err(LiqPoolError::InvalidInputData(
-                "tried to remove more liquidity than it was possible with currently minted tokens"
-                    .to_string(),
+        if lp_token_amount == 0 {
+            return Err(LiqPoolError::invalid_input("cannot remove zero liquidity"));
+        }
+        self.check_initialized()?;
+        let (token_amount, st_token_amount) = self.lp_to_underlying(lp_token_amount)?;
+        if token_amount == 0 && st_token_amount == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "lp token amount is too small to pay out anything",
             ));
         }
-
-        let token_amount = propotion(lp_token_amount, self.token, self.lp_token_supply)?;
-        let st_token_amount = propotion(lp_token_amount, self.st_token, self.lp_token_supply)?;
+        // Share of principal is rounded down, so the rest of liquidity
+        // providers is never owed less than before.
+        self.lp_principal -= propotion(self.lp_principal, lp_token_amount, self.lp_token_supply)?;
         self.lp_token_supply -= lp_token_amount;
         self.token -= token_amount;
         self.st_token -= st_token_amount;
+        self.assert_invariants();
         Ok((token_amount, st_token_amount))
     }
 
+    /// Remove whole position of `lp_token_owned` lp tokens from the pool.
+    ///
+    /// Works like `remove_liquidity`, but when the position is the whole
+    /// supply of lp tokens, both reserves are paid out in full.
+    pub fn remove_all_liquidity(&mut self, lp_token_owned: u64) -> Result<(u64, u64)> {
+        if lp_token_owned > self.lp_token_supply {
+            return Err(LiqPoolError::invalid_input(
+                "owned lp tokens exceed supply of lp tokens",
+            ));
+        }
+        if lp_token_owned > 0 && lp_token_owned == self.lp_token_supply {
+            let reserves = (self.token, self.st_token);
+            self.token = 0;
+            self.st_token = 0;
+            self.lp_token_supply = 0;
+            self.lp_principal = 0;
+            self.assert_invariants();
+            return Ok(reserves);
+        }
+        self.remove_liquidity(lp_token_owned)
+    }
+
+    /// Compute `(token, st_token)` claimed by `lp_token_amount` lp tokens,
+    /// the same amounts `remove_liquidity` pays out for them.
+    pub fn lp_to_underlying(&self, lp_token_amount: u64) -> Result<(u64, u64)> {
+        if lp_token_amount > self.lp_token_supply {
+            return Err(LiqPoolError::invalid_input(
+                "tried to remove more liquidity than it was possible with currently minted tokens",
+            ));
+        }
+        if self.lp_token_supply == 0 {
+            return Ok((0, 0));
+        }
+        Ok((
+            propotion(lp_token_amount, self.token, self.lp_token_supply)?,
+            propotion(lp_token_amount, self.st_token, self.lp_token_supply)?,
+        ))
+    }
+
+    /// Simulate removing `numerator / denominator` of `lp_token_owned` from
+    /// the pool.
+    pub fn remove_liquidity_by_fraction(
+        &mut self,
+        lp_token_owned: u64,
+        numerator: u64,
+        denominator: u64,
+    ) -> Result<(u64, u64)> {
+        if denominator == 0 || numerator > denominator {
+            return Err(LiqPoolError::invalid_input(
+                "fraction of liquidity to remove has to be between 0 and 1",
+            ));
+        }
+        self.remove_liquidity(propotion(lp_token_owned, numerator, denominator)?)
+    }
+
     /// Simulate immediate unstake operation.
     ///
     /// User may request immediate unstake operation which allows getting
     /// tokens back, without delay, for a fee that depends lineary on current
     /// liquidity of the pool.
     pub fn swap(&mut self, st_token_amount: u64) -> Result<u64> {
+        self.swap_detailed(st_token_amount)
+            .map(|receipt| receipt.token_out)
+    }
+
+    /// Same as `swap`, but reports fee the swap was charged as well.
+    pub fn swap_detailed(&mut self, st_token_amount: u64) -> Result<SwapReceipt> {
+        let token_amount = value(st_token_amount, self.price)?;
+        let fee_rate = self.linear_fee(token_amount)?;
+        let token_out = self.swap_with_fee(st_token_amount, fee_rate)?;
+        Ok(SwapReceipt {
+            token_out,
+            fee_rate,
+            fee_amount: token_amount - token_out,
+        })
+    }
+
+    /// Simulate immediate unstake operation, failing if it would pay out less
+    /// than `min_out` token.
+    ///
+    /// Protects swapper from state of the pool changing between quoting and
+    /// executing the swap. Pool is not modified when the swap fails.
+    pub fn swap_with_min_out(&mut self, st_token_amount: u64, min_out: u64) -> Result<u64> {
         let token_amount = value(st_token_amount, self.price)?;
         let fee = self.linear_fee(token_amount)?;
-        let out_token_amount = apply_fee(token_amount, fee)?;
-        if out_token_amount > self.token {
-            return Err(LiqPoolError::InsufficientLiquidity);
+        let out_token_amount = self.quote_with_fee(st_token_amount, fee)?;
+        if out_token_amount < min_out {
+            return Err(LiqPoolError::SlippageExceeded {
+                expected: min_out,
+                actual: out_token_amount,
+            });
         }
-
-        self.token -= out_token_amount;
-        self.st_token += st_token_amount;
-        Ok(out_token_amount)
+        self.swap_with_fee(st_token_amount, fee)
     }
 
-    /// Compute fee based on st_token_amount swapped and current state of
-    /// liquidity pool.
-    fn linear_fee(&self, token_amount: u64) -> Result<u64> {
-        if token_amount > self.token {
-            return Ok(self.max_fee);
-        }
-        // Fee is computed based on liquidity AFTER swap operation.
-        let liq_after = self.token - token_amount;
-        if liq_after >= self.liq_target {
-            Ok(self.min_fee)
-        } else {
-            Ok(self.max_fee - propotion(self.max_fee - self.min_fee, liq_after, self.liq_target)?)
-        }
+    /// Fee, as fraction of UNIT, a swap of `st_token_amount` would be
+    /// charged in current state of the pool.
+    pub fn current_fee(&self, st_token_amount: u64) -> Result<u64> {
+        self.linear_fee(value(st_token_amount, self.price)?)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Sample `current_fee` at `points` st_token amounts spread evenly from
+    /// 0 to `max_st_token`, both included.
+    #[cfg(feature = "alloc")]
+    pub fn fee_curve_samples(&self, max_st_token: u64, points: u32) -> Result<Vec<(u64, u64)>> {
+        (0..points)
+            .map(|i| {
+                let st_token_amount = if points == 1 {
+                    0
+                } else {
+                    propotion(max_st_token, i as u64, points as u64 - 1)?
+                };
+                Ok((st_token_amount, self.current_fee(st_token_amount)?))
+            })
+            .collect()
+    }
 
-    fn get_example_lp() -> LiqPool {
-        LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 100000 * UNIT, 2 * UNIT)
+    /// Preview immediate unstake operation without modifying the pool.
+    ///
+    /// Returns token amount swap of `st_token_amount` would pay out and the
+    /// fee it would be charged. Fails the same way `swap` would.
+    pub fn quote_swap(&self, st_token_amount: u64) -> Result<(u64, u64)> {
+        let token_amount = value(st_token_amount, self.price)?;
+        let fee = self.linear_fee(token_amount)?;
+        Ok((self.quote_with_fee(st_token_amount, fee)?, fee))
     }
 
-    /* Simple testing single operations */
+    /// Simulate immediate unstake operation paying out at least
+    /// `desired_token_out` token, returning how much st_token was swapped.
+    ///
+    /// Swapped st_token amount is found around the smaller root of the
+    /// payout curve, see `smallest_st_token_for_out`.
+    pub fn swap_exact_out(&mut self, desired_token_out: u64) -> Result<u64> {
+        if desired_token_out > self.token {
+            return Err(LiqPoolError::InsufficientLiquidity {
+                requested: desired_token_out,
+                available: self.token,
+            });
+        }
+        let token_amount = self.token_amount_for_out(desired_token_out)?;
+        let estimate =
+            u64::try_from((token_amount as u128 * UNIT as u128).div_ceil(self.price as u128))
+                .unwrap_or(u64::MAX);
+        let st_token_amount = self.smallest_st_token_for_out(desired_token_out, estimate)?;
+        self.swap(st_token_amount)?;
+        Ok(st_token_amount)
+    }
 
-    // Adding liquidity should:
-    // 1. return proper amount of lp token representing shares in the pool
-    // 2. increase token amount in liq pool
-    // 3. increase amount of minted lp tokens
-    #[test]
-    fn test_adding_liquidity() {
-        let mut liq_pool = get_example_lp();
-        let lp_token_amount = liq_pool.add_liquidity(500 * UNIT).unwrap();
-        assert_eq!(lp_token_amount, 500 * UNIT);
-        assert_eq!(liq_pool.lp_token_supply, 500 * UNIT);
-        assert_eq!(liq_pool.token, 500 * UNIT);
-        assert_eq!(liq_pool.st_token, 0);
+    /// Find st_token amount paying out at least `out` token, starting from
+    /// `estimate`.
+    ///
+    /// Payout is not monotone in input, as fee rising towards max_fee can
+    /// outgrow swapped value. The answer is bracketed by steps doubling away
+    /// from the estimate and then found with binary search, taking a number
+    /// of quotes logarithmic in the error of the estimate. It is the
+    /// smallest such amount only when the bracket stays below the payout
+    /// peak, as it does for estimates near the smaller root used by
+    /// `swap_exact_out`. Amounts the pool cannot pay out for count as reaching the
+    /// output, so when there is no such amount, swap of the returned one
+    /// fails.
+    fn smallest_st_token_for_out(&self, out: u64, estimate: u64) -> Result<u64> {
+        let reaches = |st_token_amount: u64| match self.quote_swap(st_token_amount) {
+            Ok((token_amount, _)) => Ok(token_amount >= out),
+            Err(LiqPoolError::InsufficientLiquidity { .. }) | Err(LiqPoolError::Overflow) => {
+                Ok(true)
+            }
+            Err(err) => Err(err),
+        };
+        // Invariant: `high` reaches the output, `low` does not unless it is
+        // zero.
+        let (mut low, mut high) = (estimate, estimate);
+        let mut step = 1u64;
+        if reaches(estimate)? {
+            while low > 0 && reaches(low)? {
+                high = low;
+                low = low.saturating_sub(step);
+                step = step.saturating_mul(2);
+            }
+            if low == 0 && reaches(0)? {
+                return Ok(0);
+            }
+        } else {
+            while !reaches(high)? {
+                low = high;
+                high = high.saturating_add(step);
+                step = step.saturating_mul(2);
+            }
+        }
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if reaches(mid)? {
+                high = mid;
+            } else {
+                low = mid;
+            }
+        }
+        Ok(high)
     }
 
-    // Removing liquidity should:
-    // 1. return proper amount of token and st token
-    // 2. decrease amount of token and st token in pool
-    // 3. decrease amount of minted tokens
-    #[test]
-    fn test_removing_liquidity() {
-        let mut liq_pool = get_example_lp();
-        liq_pool.token = 500 * UNIT;
-        liq_pool.st_token = 100 * UNIT;
-        liq_pool.lp_token_supply = 600 * UNIT;
+    /// Estimate token value that has to be swapped to get `out` token.
+    ///
+    /// Let g be the swapped value, T token reserve, L liq_target and U
+    /// UNIT. Output is `g * (U - fee) / U`, where fee depends on liquidity
+    /// `T - g` after swap:
+    /// - when T - g >= L fee is min_fee and g = out * U / (U - min_fee),
+    /// - when g > T fee is max_fee and g = out * U / (U - max_fee),
+    /// - otherwise fee = max_fee - (max_fee - min_fee) * (T - g) / L, and
+    ///   with k = (max_fee - min_fee) / L
+    ///   `out * U = g * (U - max_fee + k * (T - g))`, i.e.
+    ///   `k * g^2 - (U - max_fee + k * T) * g + out * U = 0`.
+    ///   The smaller root is the first g reaching the output. It is
+    ///   computed as `2c / (b + sqrt(b^2 - 4kc))`, which unlike
+    ///   `(b - sqrt(b^2 - 4kc)) / 2k` does not subtract nearly equal
+    ///   numbers, so it stays precise for large reserves.
+    fn token_amount_for_out(&self, out: u64) -> Result<u64> {
+        // No output can be bought with fee of 100%, however much token is
+        // in the pool.
+        let gross_for_fee = |fee: u64| -> Result<u64> {
+            if fee >= UNIT {
+                return Err(LiqPoolError::invalid_input(
+                    "no output can be bought with fee of 100%",
+                ));
+            }
+            net_to_gross_round_up(out, fee)
+        };
 
-        let (token_amount, st_token_amount) = liq_pool.remove_liquidity(300 * UNIT).unwrap();
-        assert_eq!(token_amount, 250 * UNIT);
-        assert_eq!(st_token_amount, 50 * UNIT);
-        assert_eq!(liq_pool.token, 250 * UNIT);
-        assert_eq!(liq_pool.st_token, 50 * UNIT);
-        assert_eq!(liq_pool.lp_token_supply, 300 * UNIT);
+        let token_amount = gross_for_fee(self.min_fee)?;
+        if self.liq_target == 0 || token_amount <= self.token.saturating_sub(self.liq_target) {
+            return Ok(token_amount);
+        }
+        if out > apply_fee_round_up(self.token, self.max_fee)? {
+            return gross_for_fee(self.max_fee);
+        }
 
-        let (token_amount, st_token_amount) = liq_pool.remove_liquidity(300 * UNIT).unwrap();
-        assert_eq!(token_amount, 250 * UNIT);
-        assert_eq!(st_token_amount, 50 * UNIT);
-        assert_eq!(liq_pool.token, 0);
-        assert_eq!(liq_pool.st_token, 0);
-        assert_eq!(liq_pool.lp_token_supply, 0);
+        let unit = UNIT as f64;
+        let k = (self.max_fee - self.min_fee) as f64 / self.liq_target as f64;
+        let b = unit - self.max_fee as f64 + k * self.token as f64;
+        let c = out as f64 * unit;
+        let token_amount = 2.0 * c / (b + sqrt(b * b - 4.0 * k * c));
+        Ok(ceil(token_amount))
     }
 
-    // Tests based on examples in marinade docs
-    // https://docs.marinade.finance/marinade-protocol/system-overview/unstake-liquidity-pool
-
-    #[test]
-    fn test_linear_fee_with_target_reached() {
-        let mut liq_pool = get_example_lp();
-        liq_pool.add_liquidity(581250 * UNIT).unwrap();
-        assert_eq!(liq_pool.linear_fee(90 * UNIT).unwrap(), 3 * UNIT / 1000);
+    /// Compute the largest st_token amount `swap` would accept.
+    ///
+    /// Swap of st_token worth g pays out `g - ceil(g * fee / UNIT)`, where
+    /// fee grows as liquidity `token - g` left after swap drops, up to
+    /// max_fee when g exceeds the whole token reserve. Swap fails when the
+    /// payout exceeds token reserve. Payout itself can drop as input grows,
+    /// but feasibility is monotone: every g up to the token reserve pays
+    /// out less than the reserve, and above it fee stays at max_fee, so
+    /// payout only grows. The largest accepted input is therefore found
+    /// with binary search.
+    pub fn max_swappable(&self) -> Result<u64> {
+        let (mut low, mut high) = (0, u64::MAX);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            match self.quote_swap(mid) {
+                Ok(_) => low = mid,
+                Err(LiqPoolError::InsufficientLiquidity { .. }) | Err(LiqPoolError::Overflow) => {
+                    high = mid - 1
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(low)
     }
 
-    #[test]
-    fn test_linear_fee_with_target_not_reached() {
-        let mut liq_pool = get_example_lp();
-        liq_pool.add_liquidity(100030 * UNIT).unwrap();
-        assert_eq!(
-            liq_pool.linear_fee(9030 * UNIT).unwrap(),
-            543 * UNIT / 100000
-        );
+    /// Simulate immediate unstake operation swapping as much of
+    /// `st_token_amount` as the pool can pay out for.
+    pub fn swap_partial(&mut self, st_token_amount: u64) -> Result<PartialSwap> {
+        let st_token_consumed = st_token_amount.min(self.max_swappable()?);
+        let token_out = if st_token_consumed > 0 {
+            self.swap(st_token_consumed)?
+        } else {
+            0
+        };
+        Ok(PartialSwap {
+            st_token_consumed,
+            token_out,
+            st_token_remaining: st_token_amount - st_token_consumed,
+        })
     }
 
-    fn generic_swapping_test(init_liq: u64, swap_amount: u64, expected_token_amount: u64) {
-        let mut liq_pool = get_example_lp();
-        liq_pool.add_liquidity(init_liq).unwrap();
-        let token_amount = liq_pool.swap(swap_amount).unwrap();
-        assert_eq!(token_amount, expected_token_amount);
-        assert_eq!(liq_pool.token, init_liq - token_amount);
-        assert_eq!(liq_pool.st_token, swap_amount);
-        assert_eq!(liq_pool.lp_token_supply, init_liq);
+    /// Same as `swap_partial`, but returns only token paid out, which is
+    /// zero when nothing can be swapped or the swap fails otherwise.
+    pub fn saturating_swap(&mut self, st_token_amount: u64) -> u64 {
+        self.swap_partial(st_token_amount)
+            .map(|partial| partial.token_out)
+            .unwrap_or(0)
     }
 
-    #[test]
-    fn test_swapping_with_target_reached() {
-        generic_swapping_test(581250 * UNIT, 45 * UNIT, 8973 * UNIT / 100);
+    /// Simulate immediate unstake operation with fee capped at `fee_cap`.
+    ///
+    /// Whenever fee from the curve is higher than `fee_cap`, swapper pays
+    /// only `fee_cap`. The difference is a subsidy paid by liquidity
+    /// providers, who collect less fee than the curve wants for the
+    /// liquidity taken out of the pool.
+    pub fn swap_fee_capped(&mut self, st_token_amount: u64, fee_cap: u64) -> Result<u64> {
+        let token_amount = value(st_token_amount, self.price)?;
+        let fee = self.linear_fee(token_amount)?.min(fee_cap);
+        self.swap_with_fee(st_token_amount, fee)
     }
 
-    #[test]
-    fn test_swapping_with_target_not_reached() {
+    /// Execute swap of `st_token_amount` charging given `fee`.
+    fn swap_with_fee(&mut self, st_token_amount: u64, fee: u64) -> Result<u64> {
+        if st_token_amount == 0 {
+            return Err(LiqPoolError::invalid_input("cannot swap zero st_token"));
+        }
+        let out_token_amount = self.quote_with_fee(st_token_amount, fee)?;
+        let token_amount = value(st_token_amount, self.price)?;
+        // Both are checked by the quote.
+        self.token -= out_token_amount;
+        self.st_token += st_token_amount;
+        // Fee stays in the pool as token, it is only counted here. It is
+        // rounded up to whole lamports, so no fraction of it is lost and
+        // there is no remainder to carry over to the next swap.
+        self.collected_fees = self
+            .collected_fees
+            .saturating_add(token_amount - out_token_amount);
+        self.swap_count = self.swap_count.saturating_add(1);
+        self.total_st_token_volume = self.total_st_token_volume.saturating_add(st_token_amount);
+        if fee >= self.max_fee {
+            self.touched_max_fee = true;
+        }
+        self.assert_invariants();
+        Ok(out_token_amount)
+    }
+
+    /// Compute token paid out by swap of `st_token_amount` charging given
+    /// `fee`, checking that the pool can afford it.
+    fn quote_with_fee(&self, st_token_amount: u64, fee: u64) -> Result<u64> {
+        self.check_initialized()?;
+        let token_amount = value(st_token_amount, self.price)?;
+        let out_token_amount = apply_fee_round_up(token_amount, fee)?;
+        if out_token_amount > self.token {
+            return Err(LiqPoolError::InsufficientLiquidity {
+                requested: out_token_amount,
+                available: self.token,
+            });
+        }
+        if self.st_token.checked_add(st_token_amount).is_none() {
+            return Err(LiqPoolError::Overflow);
+        }
+        Ok(out_token_amount)
+    }
+
+    /// Simulate settling st_token from the pool through delayed unstake.
+    ///
+    /// `st_token_amount` is taken out of the pool and its value at current
+    /// price is returned to the pool as token.
+    pub fn settle_st_token(&mut self, st_token_amount: u64) -> Result<u64> {
+        let token_amount = value(st_token_amount, self.price)?;
+        self.rebalance(st_token_amount, token_amount)?;
+        Ok(token_amount)
+    }
+
+    /// Simulate converting `st_token_to_convert` from the pool back to
+    /// token through delayed unstake, which paid out `token_received`.
+    ///
+    /// Unlike `settle_st_token`, received amount is given by the caller,
+    /// ex. when price of st_token changed while waiting for the unstake.
+    ///
+    /// Rebalance which would empty the pool while lp tokens are still
+    /// outstanding is rejected.
+    pub fn rebalance(&mut self, st_token_to_convert: u64, token_received: u64) -> Result<()> {
+        if st_token_to_convert > self.st_token {
+            return Err(LiqPoolError::invalid_input(
+                "tried to settle more st_token than there is in the pool",
+            ));
+        }
+        let token = self
+            .token
+            .checked_add(token_received)
+            .ok_or(LiqPoolError::Overflow)?;
+        let st_token = self.st_token - st_token_to_convert;
+        check_reserves(token, st_token, self.lp_token_supply)?;
+        self.token = token;
+        self.st_token = st_token;
+        Ok(())
+    }
+
+    /// Execute `swaps` at current price and update st_token price to
+    /// `new_price` afterwards.
+    ///
+    /// Either all swaps succeed or the pool is left unchanged.
+    pub fn advance_epoch(&mut self, swaps: &[u64], new_price: u64) -> Result<EpochResult> {
+        let mut liq_pool = self.clone();
+        let value_before = liq_pool.pool_value()?;
+        for &st_token_amount in swaps {
+            liq_pool.swap(st_token_amount)?;
+        }
+        let value_after_swaps = liq_pool.pool_value()?;
+        liq_pool.update_price(new_price)?;
+        liq_pool.current_epoch += 1;
+        let value_after = liq_pool.pool_value()?;
+        *self = liq_pool;
+        Ok(EpochResult {
+            fee_value_gained: value_after_swaps - value_before,
+            price_value_gained: value_after as i128 - value_after_swaps as i128,
+        })
+    }
+
+    /// Start next epoch charging fees between `new_min_fee` and
+    /// `new_max_fee`.
+    ///
+    /// Fees are validated the same way as by `new`, invalid ones leave the
+    /// pool unchanged.
+    pub fn advance_fee_epoch(&mut self, new_max_fee: u64, new_min_fee: u64) -> Result<()> {
+        check_fees(new_max_fee, new_min_fee)?;
+        self.max_fee = new_max_fee;
+        self.min_fee = new_min_fee;
+        self.current_epoch += 1;
+        Ok(())
+    }
+
+    /// Number of epochs started since creation of the pool.
+    pub fn current_epoch(&self) -> u64 {
+        self.current_epoch
+    }
+
+    /// Lazily swap each of `amounts` st_token, as results are pulled from
+    /// returned iterator.
+    ///
+    /// Unlike `process_swaps` nothing is collected, and swaps not pulled
+    /// before the iterator is dropped are never executed. Failed swap is
+    /// yielded as error and iteration goes on with the next amount.
+    pub fn swap_iter<'a, I: Iterator<Item = u64> + 'a>(
+        &'a mut self,
+        amounts: I,
+    ) -> impl Iterator<Item = Result<u64>> + 'a {
+        amounts.map(move |st_token_amount| self.swap(st_token_amount))
+    }
+
+    /// Execute swaps of `amounts` st_token in order, stopping at the first
+    /// one that fails.
+    ///
+    /// Failed swap does not modify the pool, so it is left in state after
+    /// the last successful swap. The failure is reported in `failed_at`
+    /// instead of returning error.
+    #[cfg(feature = "alloc")]
+    pub fn process_swaps(&mut self, amounts: &[u64]) -> Result<BatchResult> {
+        let mut result = BatchResult {
+            outputs: Vec::with_capacity(amounts.len()),
+            total_token_out: 0,
+            total_fee: 0,
+            failed_at: None,
+        };
+        for (i, &st_token_amount) in amounts.iter().enumerate() {
+            let receipt = match self.swap_detailed(st_token_amount) {
+                Ok(receipt) => receipt,
+                Err(_) => {
+                    result.failed_at = Some(i);
+                    break;
+                }
+            };
+            result.outputs.push(receipt.token_out);
+            result.total_token_out = result
+                .total_token_out
+                .checked_add(receipt.token_out)
+                .ok_or(LiqPoolError::Overflow)?;
+            result.total_fee = result
+                .total_fee
+                .checked_add(receipt.fee_amount)
+                .ok_or(LiqPoolError::Overflow)?;
+        }
+        Ok(result)
+    }
+
+    /// Apply single operation to the pool, discarding its output.
+    pub fn apply_op(&mut self, op: Op) -> Result<()> {
+        match op {
+            Op::AddLiquidity(token_amount) => self.add_liquidity(token_amount).map(|_| ()),
+            Op::RemoveLiquidity(lp_token_amount) => {
+                self.remove_liquidity(lp_token_amount).map(|_| ())
+            }
+            Op::Swap(st_token_amount) => self.swap(st_token_amount).map(|_| ()),
+        }
+    }
+
+    /// Same as `add_liquidity`, but returns event describing the deposit
+    /// along with its result.
+    pub fn add_liquidity_logged(&mut self, token_amount: u64) -> Result<(u64, PoolEvent)> {
+        let lp_token_minted = self.add_liquidity(token_amount)?;
+        let event = PoolEvent::AddLiquidity {
+            token_in: token_amount,
+            lp_token_minted,
+        };
+        Ok((lp_token_minted, event))
+    }
+
+    /// Same as `remove_liquidity`, but returns event describing the
+    /// withdrawal along with its result.
+    pub fn remove_liquidity_logged(
+        &mut self,
+        lp_token_amount: u64,
+    ) -> Result<((u64, u64), PoolEvent)> {
+        let (token_out, st_token_out) = self.remove_liquidity(lp_token_amount)?;
+        let event = PoolEvent::RemoveLiquidity {
+            lp_token_burned: lp_token_amount,
+            token_out,
+            st_token_out,
+        };
+        Ok(((token_out, st_token_out), event))
+    }
+
+    /// Same as `swap`, but returns event describing the swap along with its
+    /// result.
+    pub fn swap_logged(&mut self, st_token_amount: u64) -> Result<(u64, PoolEvent)> {
+        let receipt = self.swap_detailed(st_token_amount)?;
+        let event = PoolEvent::Swap {
+            st_token_in: st_token_amount,
+            token_out: receipt.token_out,
+            fee: receipt.fee_amount,
+        };
+        Ok((receipt.token_out, event))
+    }
+
+    /// Fold reserves of `other` pool into this one, returning lp tokens
+    /// minted for them.
+    ///
+    /// Pools have to share fee configuration and st_token price. Value of
+    /// `other` is minted at lp token price of this pool, the same way as
+    /// a deposit, so holders of this pool keep their claims and holders of
+    /// `other` get the minted lp tokens in proportion to their old ones.
+    /// Lifetime statistics and values owed to liquidity providers of both
+    /// pools are summed.
+    pub fn merge(&mut self, other: LiqPool<F>) -> Result<u64> {
+        if self.max_fee != other.max_fee
+            || self.min_fee != other.min_fee
+            || self.liq_target != other.liq_target
+        {
+            return Err(LiqPoolError::invalid_input(
+                "merged pools have different fee configuration",
+            ));
+        }
+        if self.price != other.price {
+            return Err(LiqPoolError::invalid_input(
+                "merged pools value st_token differently",
+            ));
+        }
+        let total_liq_pool_value = self.pool_value()?;
+        let other_value = other.pool_value()?;
+        if let Some(max_total_value) = self.max_total_value {
+            if total_liq_pool_value.saturating_add(other_value) > max_total_value {
+                return Err(LiqPoolError::CapExceeded);
+            }
+        }
+        let lp_token_to_mint = if other.lp_token_supply == 0 {
+            0
+        } else {
+            shares(other_value, total_liq_pool_value, self.lp_token_supply)?
+        };
+        if other.lp_token_supply > 0 && lp_token_to_mint == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "merged pool is too small to mint any lp tokens",
+            ));
+        }
+        let add = |a: u64, b: u64| a.checked_add(b).ok_or(LiqPoolError::Overflow);
+        let token = add(self.token, other.token)?;
+        let st_token = add(self.st_token, other.st_token)?;
+        let lp_token_supply = add(self.lp_token_supply, lp_token_to_mint)?;
+        let lp_principal = add(self.lp_principal, other.lp_principal)?;
+        self.token = token;
+        self.st_token = st_token;
+        self.lp_token_supply = lp_token_supply;
+        self.lp_principal = lp_principal;
+        self.collected_fees = self.collected_fees.saturating_add(other.collected_fees);
+        self.swap_count = self.swap_count.saturating_add(other.swap_count);
+        self.total_st_token_volume = self
+            .total_st_token_volume
+            .saturating_add(other.total_st_token_volume);
+        self.touched_max_fee |= other.touched_max_fee;
+        self.assert_invariants();
+        Ok(lp_token_to_mint)
+    }
+
+    /// Save current state of the pool.
+    pub fn checkpoint(&self) -> PoolCheckpoint<F> {
+        PoolCheckpoint {
+            state: self.clone(),
+        }
+    }
+
+    /// Bring the pool back to the state saved in `checkpoint`, undoing all
+    /// operations done since.
+    pub fn restore(&mut self, checkpoint: PoolCheckpoint<F>) {
+        *self = checkpoint.state;
+    }
+
+    /// Run `op` on a clone of the pool, returning the modified clone along
+    /// with result of `op`. The pool itself is not modified.
+    pub fn simulate<O, T>(&self, op: O) -> (Self, T)
+    where
+        O: FnOnce(&mut Self) -> T,
+    {
+        let mut liq_pool = self.clone();
+        let result = op(&mut liq_pool);
+        (liq_pool, result)
+    }
+
+    /// Compute the largest drop of token reserve below its starting level
+    /// while applying `ops` in order.
+    ///
+    /// Operations are applied on a clone, so the pool is not modified.
+    pub fn max_drawdown(&self, ops: &[Op]) -> Result<u64> {
+        let mut liq_pool = self.clone();
+        let mut min_token = self.token;
+        for op in ops {
+            liq_pool.apply_op(*op)?;
+            min_token = min_token.min(liq_pool.token);
+        }
+        Ok(self.token - min_token)
+    }
+
+    /// Apply operation unless an operation with the same nonce has been
+    /// applied recently.
+    ///
+    /// Only nonces of successful operations are remembered, so failed
+    /// operation may be retried with the same nonce. Pool keeps track of the
+    /// last `nonce_window` nonces.
+    #[cfg(feature = "alloc")]
+    pub fn apply_op_with_nonce(&mut self, op: Op, nonce: u64) -> Result<()> {
+        if self.seen_nonces.contains(&nonce) {
+            return Err(LiqPoolError::DuplicateOperation(nonce));
+        }
+        self.apply_op(op)?;
+        if self.nonce_window > 0 {
+            if self.seen_nonces.len() == self.nonce_window {
+                self.seen_nonces.pop_front();
+            }
+            self.seen_nonces.push_back(nonce);
+        }
+        Ok(())
+    }
+
+    /// Set how many recently applied nonces are remembered.
+    #[cfg(feature = "alloc")]
+    pub fn set_nonce_window(&mut self, nonce_window: usize) {
+        while self.seen_nonces.len() > nonce_window {
+            self.seen_nonces.pop_front();
+        }
+        self.nonce_window = nonce_window;
+    }
+
+    /// Compute how many token swapper loses to integer rounding in swap of
+    /// `st_token_amount`, rounded up to whole units.
+    ///
+    /// Output of swap is compared with exact (not rounded) value of the
+    /// same swap with the same fee.
+    pub fn rounding_loss_on_swap(&self, st_token_amount: u64) -> Result<u64> {
+        let token_amount = value(st_token_amount, self.price)?;
+        let fee = self.linear_fee(token_amount)?;
+        let (out_token_amount, _) = self.quote_swap(st_token_amount)?;
+
+        let unit = UNIT as u128;
+        let net_fraction = unit
+            .checked_sub(fee as u128)
+            .ok_or_else(|| LiqPoolError::invalid_input("fee cannot be greater than 100%"))?;
+        let exact_out = (st_token_amount as u128 * self.price as u128)
+            .checked_mul(net_fraction)
+            .ok_or(LiqPoolError::Overflow)?;
+        let actual_out = out_token_amount as u128 * unit * unit;
+        let loss = exact_out.saturating_sub(actual_out);
+        u64::try_from(loss.div_ceil(unit * unit)).map_err(|_| LiqPoolError::Overflow)
+    }
+
+    /// Summarize costs of swap of `st_token_amount` without executing it.
+    pub fn swap_cost_summary(&self, st_token_amount: u64) -> Result<SwapCostSummary> {
+        if st_token_amount == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "cannot summarize swap of zero st_token",
+            ));
+        }
+        let (token_out, fee) = self.quote_swap(st_token_amount)?;
+        let fair_value = value(st_token_amount, self.price)?;
+        let fee_token = propotion(fair_value, fee, UNIT)?;
+        // Swap rounds the fee up, so the remainder is below one unit.
+        let rounding_loss = fair_value - token_out - fee_token;
+        let fee_bps = if fair_value == 0 {
+            0
+        } else {
+            propotion(fee_token, BPS_SCALE, fair_value)? as u16
+        };
+        Ok(SwapCostSummary {
+            fair_value,
+            token_out,
+            fee_token,
+            rounding_loss,
+            effective_rate: propotion(token_out, UNIT, st_token_amount)?,
+            fee_bps,
+        })
+    }
+
+    /// Compute swap fee now and after hypothetical deposit of `pending_deposit`
+    /// token into the pool.
+    ///
+    /// Deposit is added to token reserve of a clone, so the pool is not
+    /// modified and deposit rules like the cap do not apply. Zero deposit
+    /// leaves the fee unchanged. If the deposit lifts liquidity after swap
+    /// above liq_target, the second fee falls straight to min_fee.
+    pub fn fee_after_pending_deposit(
+        &self,
+        st_token_amount: u64,
+        pending_deposit: u64,
+    ) -> Result<(u64, u64)> {
+        let token_amount = value(st_token_amount, self.price)?;
+        let fee_now = self.linear_fee(token_amount)?;
+        let mut after_deposit = self.clone();
+        after_deposit.token = after_deposit
+            .token
+            .checked_add(pending_deposit)
+            .ok_or(LiqPoolError::Overflow)?;
+        let fee_after = after_deposit.linear_fee(token_amount)?;
+        Ok((fee_now, fee_after))
+    }
+
+    /// Count how many consecutive swaps of `chunk` st_token can be executed
+    /// while still paying only min_fee.
+    ///
+    /// Swaps are simulated on a clone with the pool's fee curve, stopping
+    /// at the first one charging other fee than min_fee or failing.
+    /// Saturates at `u32::MAX`, also when swaps take nothing out of the
+    /// pool, so the fee can never change.
+    pub fn swaps_until_max_fee(&self, chunk: u64) -> Result<u32> {
+        let token_amount = value(chunk, self.price)?;
+        if token_amount == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "swapped amount is worth nothing",
+            ));
+        }
+        let mut liq_pool = self.clone();
+        let mut count = 0;
+        while count < u32::MAX {
+            let fee = liq_pool.linear_fee(token_amount)?;
+            if fee != liq_pool.min_fee {
+                break;
+            }
+            let token_before = liq_pool.token;
+            if liq_pool.swap_with_fee(chunk, fee).is_err() {
+                break;
+            }
+            if liq_pool.token == token_before {
+                return Ok(u32::MAX);
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Split swap of `total_st_token` into two consecutive swaps paying
+    /// (as close as possible) the same fee.
+    ///
+    /// Fee of the first swap grows with its size, while fee of the second
+    /// one shrinks, because less is left to swap. The split is found with
+    /// binary search over size of the first swap, simulated on clones. When
+    /// whole amount is swapped for min_fee, every split is equal and
+    /// amount is halved.
+    pub fn fee_equalizing_split(&self, total_st_token: u64) -> Result<(u64, u64)> {
+        let fees_of_split = |first: u64| -> Result<(u64, u64)> {
+            let first_fee = self.linear_fee(value(first, self.price)?)?;
+            let mut liq_pool = self.clone();
+            let first_swap = if first > 0 {
+                liq_pool.swap(first)
+            } else {
+                Ok(0)
+            };
+            let second_fee = match first_swap {
+                Ok(_) => liq_pool.linear_fee(value(total_st_token - first, self.price)?)?,
+                Err(LiqPoolError::InsufficientLiquidity { .. }) => self.max_fee,
+                Err(err) => return Err(err),
+            };
+            Ok((first_fee, second_fee))
+        };
+
+        if self.linear_fee(value(total_st_token, self.price)?)? == self.min_fee {
+            let first = total_st_token / 2;
+            return Ok((first, total_st_token - first));
+        }
+        // Find the smallest first swap not cheaper than the second one.
+        let (mut low, mut high) = (0, total_st_token);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let (first_fee, second_fee) = fees_of_split(mid)?;
+            if first_fee >= second_fee {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        // Check that the split can be executed at all.
+        let mut liq_pool = self.clone();
+        for st_token_amount in [low, total_st_token - low] {
+            if st_token_amount > 0 {
+                liq_pool.swap(st_token_amount)?;
+            }
+        }
+        Ok((low, total_st_token - low))
+    }
+
+    /// Volume weighted average fee of swaps of `volume_samples` st_token,
+    /// each one priced independently against the current state of the pool.
+    pub fn expected_fee(&self, volume_samples: &[u64]) -> Result<u64> {
+        let mut total_volume: u128 = 0;
+        let mut weighted_fees: u128 = 0;
+        for &st_token_amount in volume_samples {
+            let token_amount = value(st_token_amount, self.price)?;
+            let fee = self.linear_fee(token_amount)?;
+            total_volume += token_amount as u128;
+            weighted_fees += token_amount as u128 * fee as u128;
+        }
+        if total_volume == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "volume samples do not contain any volume",
+            ));
+        }
+        u64::try_from(weighted_fees / total_volume).map_err(|_| LiqPoolError::Overflow)
+    }
+
+    /// Volume weighted average fee of swapping `total_st_token` in `chunks`
+    /// equal swaps one after another, the last one taking the remainder.
+    ///
+    /// Each chunk drains liquidity seen by the next one, but is charged
+    /// only for liquidity left after itself, so splitting a swap below the
+    /// liquidity target lowers its average fee. Swaps are done on a clone.
+    pub fn average_fee_for_split(&self, total_st_token: u64, chunks: u32) -> Result<u64> {
+        if chunks == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "swap has to be split into at least one chunk",
+            ));
+        }
+        let chunk = total_st_token / chunks as u64;
+        let mut liq_pool = self.clone();
+        let mut total_volume: u128 = 0;
+        let mut weighted_fees: u128 = 0;
+        for i in 0..chunks {
+            let st_token_amount = if i == chunks - 1 {
+                total_st_token - chunk * (chunks as u64 - 1)
+            } else {
+                chunk
+            };
+            let token_amount = value(st_token_amount, self.price)?;
+            let receipt = liq_pool.swap_detailed(st_token_amount)?;
+            total_volume += token_amount as u128;
+            weighted_fees += token_amount as u128 * receipt.fee_rate as u128;
+        }
+        if total_volume == 0 {
+            return Err(LiqPoolError::invalid_input(
+                "swapped amount is worth nothing",
+            ));
+        }
+        u64::try_from(weighted_fees / total_volume).map_err(|_| LiqPoolError::Overflow)
+    }
+
+    /// Swap `st_token_amount` and settle the same st_token right after,
+    /// returning token reserve before minus token reserve after.
+    ///
+    /// Settling returns to the pool full value of swapped st_token, so if
+    /// swap and settle value st_token the same way, result is minus fee
+    /// kept by the pool. Computed on a clone.
+    pub fn swap_then_settle_residual(&self, st_token_amount: u64) -> Result<i128> {
+        let mut liq_pool = self.clone();
+        liq_pool.swap(st_token_amount)?;
+        liq_pool.settle_st_token(st_token_amount)?;
+        Ok(self.token as i128 - liq_pool.token as i128)
+    }
+
+    /// Compute token output of swapping `additional` st_token after
+    /// `already_routed` st_token has already been swapped in this pool.
+    ///
+    /// Both swaps are executed on a clone, so the fee of the additional
+    /// amount reflects liquidity drained by the routed one.
+    pub fn marginal_output(&self, already_routed: u64, additional: u64) -> Result<u64> {
+        let mut liq_pool = self.clone();
+        if already_routed > 0 {
+            liq_pool.swap(already_routed)?;
+        }
+        liq_pool.swap(additional)
+    }
+
+    /// St_token amounts at which fee regime changes for current reserves.
+    ///
+    /// First is the largest swap still paying min_fee (liquidity after swap
+    /// stays at liq_target), second is the swap taking value of the whole
+    /// token reserve, at which fee reaches max_fee.
+    pub fn regime_boundaries(&self) -> Result<(u64, u64)> {
+        let min_fee_boundary =
+            amount_from_value(self.token.saturating_sub(self.liq_target), self.price)?;
+        let max_fee_boundary = amount_from_value(self.token, self.price)?;
+        Ok((min_fee_boundary, max_fee_boundary))
+    }
+
+    /// Preview output of swap of `st_token_amount` reduced by `margin_bps`
+    /// basis points and rounded down.
+    ///
+    /// Result is a safe minimum to promise, even if state of the pool
+    /// slightly drifts before the swap is executed.
+    pub fn preview_swap_conservative(&self, st_token_amount: u64, margin_bps: u16) -> Result<u64> {
+        let margin_bps = margin_bps as u64;
+        if margin_bps > BPS_SCALE {
+            return Err(LiqPoolError::invalid_input(
+                "margin cannot be greater than 100%",
+            ));
+        }
+        let (out_token_amount, _) = self.quote_swap(st_token_amount)?;
+        propotion(out_token_amount, BPS_SCALE - margin_bps, BPS_SCALE)
+    }
+
+    /// Find deposit up to `max_deposit` token that earns the depositor the
+    /// most fees over the upcoming `swaps`.
+    ///
+    /// Bigger deposit gets bigger share of fees, but also lowers the fees
+    /// themselves. Deposit levels are tried on an evenly spaced grid, each
+    /// simulated on a clone. Swaps that the pool cannot honor are skipped.
+    pub fn fee_maximizing_deposit(&self, swaps: &[u64], max_deposit: u64) -> Result<u64> {
+        let mut best_deposit = 0;
+        let mut best_earnings = self.deposit_fee_earnings(0, swaps)?;
+        for step in 1..=DEPOSIT_SEARCH_STEPS {
+            let deposit = propotion(max_deposit, step, DEPOSIT_SEARCH_STEPS)?;
+            let earnings = self.deposit_fee_earnings(deposit, swaps)?;
+            if earnings > best_earnings {
+                best_deposit = deposit;
+                best_earnings = earnings;
+            }
+        }
+        Ok(best_deposit)
+    }
+
+    /// Value gained by depositing `deposit` token before `swaps`.
+    fn deposit_fee_earnings(&self, deposit: u64, swaps: &[u64]) -> Result<i128> {
+        if deposit == 0 {
+            return Ok(0);
+        }
+        let mut liq_pool = self.clone();
+        let lp_token_amount = liq_pool.add_liquidity(deposit)?;
+        for &st_token_amount in swaps {
+            match liq_pool.swap(st_token_amount) {
+                Ok(_) | Err(LiqPoolError::InsufficientLiquidity { .. }) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if lp_token_amount == 0 {
+            return Ok(0);
+        }
+        let lp_value = propotion(
+            lp_token_amount,
+            liq_pool.pool_value()?,
+            liq_pool.lp_token_supply,
+        )?;
+        Ok(lp_value as i128 - deposit as i128)
+    }
+
+    /// Simulate attacker swapping `attacker_front` st_token before victim's
+    /// swap of `victim`, returning attacker's net token gain.
+    ///
+    /// Pool swaps only st_token into token, so attacker cannot swap back.
+    /// Instead the back leg deposits `attacker_back` token as liquidity
+    /// after the victim's swap and withdraws it right away, settling
+    /// withdrawn st_token at fair value. Either leg is skipped when its
+    /// amount is zero, so `attacker_front == 0` gives the baseline of
+    /// depositing after the victim without front-running. Computed on a
+    /// clone.
+    pub fn sandwich_profit(
+        &self,
+        attacker_front: u64,
+        victim: u64,
+        attacker_back: u64,
+    ) -> Result<i128> {
+        let mut liq_pool = self.clone();
+        let mut profit = 0;
+        if attacker_front > 0 {
+            let front_out = liq_pool.swap(attacker_front)?;
+            profit += front_out as i128 - value(attacker_front, self.price)? as i128;
+        }
+        liq_pool.swap(victim)?;
+        if attacker_back > 0 {
+            let lp_token_amount = liq_pool.add_liquidity(attacker_back)?;
+            let (token_out, st_token_out) = liq_pool.remove_liquidity(lp_token_amount)?;
+            let settled = token_out as i128 + value(st_token_out, self.price)? as i128;
+            profit += settled - attacker_back as i128;
+        }
+        Ok(profit)
+    }
+
+    /// Token value of the largest swap which is charged only min_fee, zero
+    /// when the pool is at or below its liquidity target.
+    pub fn min_fee_threshold(&self) -> u64 {
+        self.token.saturating_sub(self.liq_target)
+    }
+
+    /// Compute how much token has to be deposited so that swap of
+    /// `st_token_amount` afterwards is charged only min_fee.
+    ///
+    /// Fee is based on liquidity after swap, so the deposit has to keep
+    /// `token - swapped token value` at or above liq_target.
+    pub fn deposit_to_keep_swap_cheap(&self, st_token_amount: u64) -> Result<u64> {
+        let token_amount = value(st_token_amount, self.price)?;
+        let required_liq = self
+            .liq_target
+            .checked_add(token_amount)
+            .ok_or(LiqPoolError::Overflow)?;
+        Ok(required_liq.saturating_sub(self.token))
+    }
+
+    /// Share of st_token in total value of the pool, in basis points.
+    ///
+    /// The higher it is, the more the pool value depends on st_token price.
+    pub fn st_token_exposure_bps(&self) -> Result<u16> {
+        let total_value = self.pool_value()?;
+        if total_value == 0 {
+            return Ok(0);
+        }
+        let st_token_value = value(self.st_token, self.price)?;
+        u16::try_from(propotion(st_token_value, BPS_SCALE, total_value)?)
+            .map_err(|_| LiqPoolError::CalculationError)
+    }
+
+    /// Split of value between token and st_token in the pool, as fractions
+    /// of UNIT.
+    ///
+    /// Depositing token and st_token value in this ratio does not change
+    /// composition of the pool. Empty pool accepts only token.
+    pub fn current_deposit_ratio(&self) -> Result<(u64, u64)> {
+        let total_value = self.pool_value()?;
+        if total_value == 0 {
+            return Ok((UNIT, 0));
+        }
+        let token_fraction = propotion(self.token, UNIT, total_value)?;
+        Ok((token_fraction, UNIT - token_fraction))
+    }
+
+    /// Share of token in total value of the pool, as fraction of UNIT.
+    ///
+    /// Falls toward zero as swaps replace token with st_token. Empty pool
+    /// counts as holding only token.
+    pub fn reserves_ratio(&self) -> Result<u64> {
+        Ok(self.current_deposit_ratio()?.0)
+    }
+
+    /// Whether token reserve is below liquidity target, so swaps are
+    /// charged more than min_fee.
+    pub fn is_liquidity_depleted(&self) -> bool {
+        self.token < self.liq_target
+    }
+
+    /// How close token reserve is to liquidity target, as fraction of UNIT.
+    ///
+    /// UNIT when the target is reached, falling linearly to 0 for pool
+    /// without any token. Pool with zero target, which `new` rejects anyway, is
+    /// always healthy.
+    pub fn health_factor(&self) -> u64 {
+        if self.liq_target == 0 {
+            return UNIT;
+        }
+        let liquidity = self.token.min(self.liq_target);
+        // Never above UNIT, so the cast is lossless.
+        (liquidity as u128 * UNIT as u128 / self.liq_target as u128) as u64
+    }
+
+    /// Total token withheld as fees by swaps in the lifetime of the pool,
+    /// less fees withdrawn with `withdraw_collected_fees`.
+    pub fn collected_fees(&self) -> u64 {
+        self.collected_fees
+    }
+
+    /// Value liquidity providers are owed: value of their deposits, less
+    /// the part of it paid out by removing liquidity.
+    ///
+    /// Pool rebuilt from reserves owes its whole value.
+    pub fn lp_principal(&self) -> u64 {
+        self.lp_principal
+    }
+
+    /// Fees that can be withdrawn with `withdraw_collected_fees`.
+    ///
+    /// Limited by collected fees, by token reserve and by value of the
+    /// pool above what liquidity providers are owed. Fees taken out by
+    /// removed liquidity, or lost to a drop of st_token price, cannot be
+    /// withdrawn again.
+    pub fn withdrawable_fees(&self) -> Result<u64> {
+        let above_principal = self.pool_value()?.saturating_sub(self.lp_principal);
+        Ok(self.collected_fees.min(self.token).min(above_principal))
+    }
+
+    /// Take `amount` of collected fees out of token reserve, returning the
+    /// amount to be credited to a treasury.
+    ///
+    /// Fails when `amount` exceeds `withdrawable_fees`, so the pool is left
+    /// with at least the value its liquidity providers are owed. Fees not
+    /// yet withdrawn raise price of lp tokens, and withdrawing them lowers
+    /// it back.
+    pub fn withdraw_collected_fees(&mut self, amount: u64) -> Result<u64> {
+        if amount > self.collected_fees {
+            return Err(LiqPoolError::invalid_input(
+                "cannot withdraw more fees than collected",
+            ));
+        }
+        let withdrawable = self.withdrawable_fees()?;
+        if amount > withdrawable {
+            return Err(LiqPoolError::InsufficientLiquidity {
+                requested: amount,
+                available: withdrawable,
+            });
+        }
+        check_reserves(self.token - amount, self.st_token, self.lp_token_supply)?;
+        self.token -= amount;
+        self.collected_fees -= amount;
+        self.assert_invariants();
+        Ok(amount)
+    }
+
+    /// Number of swaps in the lifetime of the pool.
+    pub fn swap_count(&self) -> u64 {
+        self.swap_count
+    }
+
+    /// Total st_token swapped in the lifetime of the pool. Saturates at
+    /// `u64::MAX`.
+    pub fn total_volume(&self) -> u64 {
+        self.total_st_token_volume
+    }
+
+    /// Whether any swap in the lifetime of the pool has been charged
+    /// max_fee.
+    pub fn has_touched_max_fee(&self) -> bool {
+        self.touched_max_fee
+    }
+
+    /// Token reserve formatted as decimal number, ex. `500.000000000`.
+    #[cfg(feature = "alloc")]
+    pub fn token_decimal(&self) -> String {
+        format_unit(self.token)
+    }
+
+    /// St_token reserve formatted as decimal number, ex. `500.000000000`.
+    #[cfg(feature = "alloc")]
+    pub fn st_token_decimal(&self) -> String {
+        format_unit(self.st_token)
+    }
+
+    /// Lp token supply formatted as decimal number, ex. `500.000000000`.
+    #[cfg(feature = "alloc")]
+    pub fn lp_supply_decimal(&self) -> String {
+        format_unit(self.lp_token_supply)
+    }
+
+    /// Canonical description of pool state with reserves and lp token price
+    /// formatted as decimals, ex.
+    /// `token=500.000000000;st_token=0.000000000;lp=500.000000000;price_per_share=1.000000000`.
+    ///
+    /// Unlike `Debug` output, format of this string is stable. Lp token
+    /// price saturates at `u64::MAX`, like `total_value`.
+    #[cfg(feature = "alloc")]
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "token={};st_token={};lp={};price_per_share={}",
+            format_unit(self.token),
+            format_unit(self.st_token),
+            format_unit(self.lp_token_supply),
+            format_unit(self.lp_token_price().unwrap_or(u64::MAX))
+        )
+    }
+
+    /// Express `accrued_fee` token as number of lp tokens that would have to
+    /// be minted at price before the accrual to represent the same value.
+    ///
+    /// Fees are not minted, they raise price of existing lp tokens. This
+    /// shows the same gain as if it was paid out in lp tokens.
+    pub fn fee_as_equivalent_lp(&self, accrued_fee: u64) -> Result<u64> {
+        shares(accrued_fee, self.pool_value()?, self.lp_token_supply)
+    }
+
+    /// Compare total value, lp token supply and utilization of the pool
+    /// with `reference` pool.
+    pub fn relative_to<G: FeeCurve + Clone>(
+        &self,
+        reference: &LiqPool<G>,
+    ) -> Result<RelativeStats> {
+        let ratio = |amount: u64, reference_amount: u64| -> Result<Option<u64>> {
+            if reference_amount == 0 {
+                return Ok(None);
+            }
+            propotion(amount, UNIT, reference_amount).map(Some)
+        };
+        Ok(RelativeStats {
+            total_value_ratio: ratio(self.pool_value()?, reference.pool_value()?)?,
+            lp_supply_ratio: ratio(self.lp_token_supply, reference.lp_token_supply)?,
+            utilization_ratio: ratio(self.utilization()?, reference.utilization()?)?,
+        })
+    }
+
+    /// Fraction of pool value, in UNIT, that has been swapped into st_token.
+    fn utilization(&self) -> Result<u64> {
+        Ok(UNIT - self.current_deposit_ratio()?.0)
+    }
+
+    /// Compute `(token, st_token)` paid out for `lp_token_amount` lp tokens,
+    /// including their share of fees accrued so far.
+    ///
+    /// Fees stay in reserves of the pool as soon as swap is executed, until
+    /// they are taken out with `withdraw_collected_fees`. Proportional share
+    /// of reserves therefore already contains the share of fees not
+    /// withdrawn so far, and there is nothing pending to add. Pool is not
+    /// modified.
+    pub fn exit_value_with_pending_fees(&self, lp_token_amount: u64) -> Result<(u64, u64)> {
+        self.clone().remove_liquidity(lp_token_amount)
+    }
+
+    /// Compute how much less value depositing `token_amount` token gets,
+    /// compared to depositing the same value split between token and
+    /// st_token in current ratio of the pool.
+    ///
+    /// Lp tokens are minted according to value of the deposit, not its
+    /// composition, so the penalty comes only from rounding. Both deposits
+    /// are simulated on clones.
+    pub fn single_sided_deposit_penalty(&self, token_amount: u64) -> Result<u64> {
+        let mut single_sided = self.clone();
+        let single_sided_lp = single_sided.add_liquidity(token_amount)?;
+
+        let (token_fraction, _) = self.current_deposit_ratio()?;
+        let matched_token = propotion(token_amount, token_fraction, UNIT)?;
+        let matched_st_token = amount_from_value(token_amount - matched_token, self.price)?;
+        let mut matched = self.clone();
+        let matched_lp = matched.deposit(matched_token, matched_st_token)?;
+
+        let single_sided_value = single_sided.lp_value(single_sided_lp)?;
+        let matched_value = matched.lp_value(matched_lp)?;
+        Ok(matched_value.saturating_sub(single_sided_value))
+    }
+
+    /// Estimate how much value of `lp_token_owned` lp tokens grows when
+    /// the pool processes `swap_volumes` st_token swaps in order.
+    ///
+    /// Swaps are applied on a clone, so the pool is not modified.
+    pub fn estimate_lp_growth(&self, lp_token_owned: u64, swap_volumes: &[u64]) -> Result<u64> {
+        let mut liq_pool = self.clone();
+        for &st_token_amount in swap_volumes {
+            liq_pool.swap(st_token_amount)?;
+        }
+        Ok(liq_pool
+            .lp_value(lp_token_owned)?
+            .saturating_sub(self.lp_value(lp_token_owned)?))
+    }
+
+    /// Value of `lp_token_amount` lp tokens in token.
+    fn lp_value(&self, lp_token_amount: u64) -> Result<u64> {
+        if self.lp_token_supply == 0 {
+            return Ok(0);
+        }
+        propotion(lp_token_amount, self.pool_value()?, self.lp_token_supply)
+    }
+
+    /// Compare value of `lp_balance` lp tokens with value of simply holding
+    /// the deposited token and st_token, both at current price.
+    ///
+    /// Positive result means providing liquidity paid off, thanks to
+    /// collected fees, despite composition of the pool drifting away from
+    /// the deposit.
+    pub fn vs_hold(
+        &self,
+        deposited_token: u64,
+        deposited_st_token: u64,
+        lp_balance: u64,
+    ) -> Result<i128> {
+        if lp_balance > self.lp_token_supply {
+            return Err(LiqPoolError::invalid_input(
+                "lp balance is greater than supply of lp tokens",
+            ));
+        }
+        let lp_value = self.lp_value(lp_balance)?;
+        let hold_value = deposited_token as i128 + value(deposited_st_token, self.price)? as i128;
+        Ok(lp_value as i128 - hold_value)
+    }
+
+    /// Compute how composition of `lp_token_amount` lp tokens, entered with
+    /// deposit of `entry_token_value` token, drifted from the deposit.
+    ///
+    /// Returns `(token, st_token)`: how much less token the lp tokens claim
+    /// than was deposited, and how much st_token they claim instead. Swaps
+    /// replace token in the pool with st_token, so both grow with volume.
+    pub fn composition_drift(
+        &self,
+        lp_token_amount: u64,
+        entry_token_value: u64,
+    ) -> Result<(u64, u64)> {
+        let (token_amount, st_token_amount) = self.lp_to_underlying(lp_token_amount)?;
+        Ok((
+            entry_token_value.saturating_sub(token_amount),
+            st_token_amount,
+        ))
+    }
+
+    /// Signed change of total pool value if st_token price moved to
+    /// `new_price`.
+    ///
+    /// Only st_token part of the pool is sensitive to price, token reserve
+    /// stays the same. Pool is not modified.
+    pub fn price_shock_impact(&self, new_price: u64) -> Result<i128> {
+        let mut shocked = self.clone();
+        shocked.price = new_price;
+        Ok(shocked.pool_value()? as i128 - self.pool_value()? as i128)
+    }
+
+    /// Total value of the pool expressed in token, with st_token valued at
+    /// current price.
+    ///
+    /// This is the only notion of pool value, used for pricing lp tokens
+    /// on deposits. Withdrawals do not need it, they pay out proportional
+    /// share of both reserves, which is worth the same share of this value.
+    fn pool_value(&self) -> Result<u64> {
+        value(self.st_token, self.price)?
+            .checked_add(self.token)
+            .ok_or(LiqPoolError::Overflow)
+    }
+
+    /// Token received per one st_token, as fraction of UNIT, for a swap of
+    /// negligible size.
+    fn marginal_rate(&self) -> Result<u64> {
+        let net_fraction = UNIT
+            .checked_sub(self.linear_fee(0)?)
+            .ok_or_else(|| LiqPoolError::invalid_input("fee cannot be greater than 100%"))?;
+        propotion(self.price, net_fraction, UNIT)
+    }
+
+    /// Find the largest swap after which marginal rate of the pool is still
+    /// at least `rate`.
+    fn swap_to_marginal_rate(&self, rate: u64) -> Result<u64> {
+        let (mut low, mut high) = (0, amount_from_value(self.token, self.price)?);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            let mut liq_pool = self.clone();
+            liq_pool.swap(mid)?;
+            if liq_pool.marginal_rate()? >= rate {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        Ok(low)
+    }
+
+    /// Check consistency of the pool state in debug builds, called after
+    /// every deposit, withdrawal and swap.
+    fn assert_invariants(&self) {
+        // Deposits are priced against total value, it has to be computable.
+        debug_assert!(self.pool_value().is_ok(), "total value overflows");
+        // Withdrawals never take the last token of a reserve while some lp
+        // tokens remain, so lp tokens exist exactly when there are reserves.
+        debug_assert_eq!(
+            self.lp_token_supply == 0,
+            self.token == 0 && self.st_token == 0,
+            "lp token supply is inconsistent with reserves"
+        );
+        // Fees are validated whenever they are set.
+        debug_assert!(
+            self.min_fee <= self.max_fee && self.max_fee <= UNIT,
+            "fees are out of range"
+        );
+    }
+
+    /// Fail with `PoolNotInitialized` unless some liquidity has been added
+    /// to the pool.
+    fn check_initialized(&self) -> Result<()> {
+        if self.lp_token_supply == 0 {
+            return Err(LiqPoolError::PoolNotInitialized);
+        }
+        Ok(())
+    }
+
+    /// Compute fee based on st_token_amount swapped and current state of
+    /// liquidity pool, unless swap drains the pool completely, which is
+    /// always charged max_fee.
+    ///
+    /// Zero liq_target is always reached, so such pool charges only min_fee.
+    ///
+    /// Liquidity is the token reserve only, not the total value. Swaps pay
+    /// out token, so st_token in the pool cannot serve them until it is
+    /// settled, however much it is worth.
+    fn linear_fee(&self, token_amount: u64) -> Result<u64> {
+        if self.liq_target == 0 {
+            return Ok(self.min_fee);
+        }
+        if token_amount > self.token {
+            return Ok(self.max_fee);
+        }
+        // Fee is computed based on liquidity AFTER swap operation.
+        let liq_after = self.token - token_amount;
+        self.fee_curve
+            .fee(liq_after, self.liq_target, self.min_fee, self.max_fee)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<F> fmt::Display for LiqPool<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "token: {}, st_token: {}, lp_token_supply: {}, min_fee: {}, max_fee: {}, liq_target: {}",
+            format_unit(self.token),
+            format_unit(self.st_token),
+            format_unit(self.lp_token_supply),
+            format_unit(self.min_fee),
+            format_unit(self.max_fee),
+            format_unit(self.liq_target)
+        )
+    }
+}
+
+fn check_reserves(token: u64, st_token: u64, lp_token_supply: u64) -> Result<()> {
+    let is_empty = token == 0 && st_token == 0;
+    if is_empty != (lp_token_supply == 0) {
+        return Err(LiqPoolError::invalid_input(
+            "lp token supply is inconsistent with reserves",
+        ));
+    }
+    Ok(())
+}
+
+fn check_price(price: u64) -> Result<()> {
+    if price == 0 {
+        return Err(LiqPoolError::invalid_input("st_token price cannot be zero"));
+    }
+    Ok(())
+}
+
+fn check_fees(max_fee: u64, min_fee: u64) -> Result<()> {
+    if max_fee < min_fee {
+        return Err(LiqPoolError::invalid_input(
+            "max fee cannot be smaller than min fee",
+        ));
+    }
+    if max_fee > UNIT {
+        return Err(LiqPoolError::invalid_input(
+            "max fee cannot be greater than 100%",
+        ));
+    }
+    Ok(())
+}
+
+/// Compute st_token amounts to swap through pools `a` and `b` so that
+/// their marginal rates (token received for the next tiny swap) meet.
+///
+/// Only the pool with better rate is swapped, until its rate falls to the
+/// rate of the other one. If that never happens, the pool is swapped up to
+/// its whole token reserve.
+pub fn parity_swap<F, G>(a: &LiqPool<F>, b: &LiqPool<G>) -> Result<(u64, u64)>
+where
+    F: FeeCurve + Clone,
+    G: FeeCurve + Clone,
+{
+    let rate_a = a.marginal_rate()?;
+    let rate_b = b.marginal_rate()?;
+    if rate_a >= rate_b {
+        Ok((a.swap_to_marginal_rate(rate_b)?, 0))
+    } else {
+        Ok((0, b.swap_to_marginal_rate(rate_a)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_example_lp() -> LiqPool {
+        LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 100000 * UNIT, 2 * UNIT).unwrap()
+    }
+
+    /* Simple testing single operations */
+
+    // Adding liquidity should:
+    // 1. return proper amount of lp token representing shares in the pool
+    // 2. increase token amount in liq pool
+    // 3. increase amount of minted lp tokens
+    #[test]
+    fn test_adding_liquidity() {
+        let mut liq_pool = get_example_lp();
+        let lp_token_amount = liq_pool.add_liquidity(500 * UNIT).unwrap();
+        assert_eq!(lp_token_amount, 500 * UNIT);
+        assert_eq!(liq_pool.lp_token_supply, 500 * UNIT);
+        assert_eq!(liq_pool.token, 500 * UNIT);
+        assert_eq!(liq_pool.st_token, 0);
+    }
+
+    #[test]
+    fn test_adding_liquidity_overflowing_reserves() {
+        let mut liq_pool = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            u64::MAX - 10,
+            0,
+            1,
+        )
+        .unwrap();
+        let before = liq_pool.clone();
+        assert!(matches!(
+            liq_pool.add_liquidity(100),
+            Err(LiqPoolError::Overflow)
+        ));
+        assert_eq!(liq_pool, before);
+
+        let mut liq_pool = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            1,
+            UNIT,
+            u64::MAX - 10,
+            UNIT,
+        )
+        .unwrap();
+        let before = liq_pool.clone();
+        assert!(matches!(
+            liq_pool.add_liquidity_both(UNIT, 100),
+            Err(LiqPoolError::Overflow)
+        ));
+        assert_eq!(liq_pool, before);
+    }
+
+    #[test]
+    fn test_adding_liquidity_with_share() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(
+            liq_pool.add_liquidity_with_share(1000 * UNIT).unwrap(),
+            (1000 * UNIT, UNIT)
+        );
+        assert_eq!(
+            liq_pool.add_liquidity_with_share(1000 * UNIT).unwrap(),
+            (1000 * UNIT, UNIT / 2)
+        );
+        assert_eq!(
+            liq_pool.add_liquidity_with_share(500 * UNIT).unwrap(),
+            (500 * UNIT, UNIT / 5)
+        );
+    }
+
+    #[test]
+    fn test_adding_liquidity_in_st_token() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.update_price(UNIT).unwrap();
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        let mut st_liq_pool = liq_pool.clone();
+        assert_eq!(
+            st_liq_pool.add_liquidity_st(500 * UNIT).unwrap(),
+            liq_pool.add_liquidity(500 * UNIT).unwrap()
+        );
+        assert_eq!(st_liq_pool.st_token, 500 * UNIT);
+        assert_eq!(st_liq_pool.token, 100 * UNIT);
+        assert_eq!(
+            st_liq_pool.add_liquidity_st(300 * UNIT).unwrap(),
+            liq_pool.add_liquidity(300 * UNIT).unwrap()
+        );
+        assert_eq!(liq_pool.lp_token_supply, st_liq_pool.lp_token_supply);
+
+        // At price of 2 st_token is worth twice as much lp tokens.
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(500 * UNIT).unwrap();
+        assert_eq!(liq_pool.add_liquidity_st(100 * UNIT).unwrap(), 200 * UNIT);
+    }
+
+    #[test]
+    fn test_adding_liquidity_both() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(
+            liq_pool.add_liquidity_both(300 * UNIT, 100 * UNIT).unwrap(),
+            500 * UNIT
+        );
+        assert_eq!(
+            (liq_pool.token, liq_pool.st_token),
+            (300 * UNIT, 100 * UNIT)
+        );
+        liq_pool.swap(45 * UNIT).unwrap();
+
+        let mut separately = liq_pool.clone();
+        let lp_token_amount = liq_pool.add_liquidity_both(123 * UNIT, 77 * UNIT).unwrap();
+        let separate_lp_token_amount = separately.add_liquidity(123 * UNIT).unwrap()
+            + separately.add_liquidity_st(77 * UNIT).unwrap();
+        assert!(lp_token_amount.abs_diff(separate_lp_token_amount) <= 1);
+        assert_eq!(liq_pool.token, separately.token);
+        assert_eq!(liq_pool.st_token, separately.st_token);
+
+        assert!(matches!(
+            get_example_lp().add_liquidity_both(0, 100 * UNIT),
+            Err(LiqPoolError::PoolNotInitialized)
+        ));
+    }
+
+    // Removing liquidity should:
+    // 1. return proper amount of token and st token
+    // 2. decrease amount of token and st token in pool
+    // 3. decrease amount of minted tokens
+    #[test]
+    fn test_removing_liquidity() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 500 * UNIT;
+        liq_pool.st_token = 100 * UNIT;
+        liq_pool.lp_token_supply = 600 * UNIT;
+
+        let (token_amount, st_token_amount) = liq_pool.remove_liquidity(300 * UNIT).unwrap();
+        assert_eq!(token_amount, 250 * UNIT);
+        assert_eq!(st_token_amount, 50 * UNIT);
+        assert_eq!(liq_pool.token, 250 * UNIT);
+        assert_eq!(liq_pool.st_token, 50 * UNIT);
+        assert_eq!(liq_pool.lp_token_supply, 300 * UNIT);
+
+        let (token_amount, st_token_amount) = liq_pool.remove_liquidity(300 * UNIT).unwrap();
+        assert_eq!(token_amount, 250 * UNIT);
+        assert_eq!(st_token_amount, 50 * UNIT);
+        assert_eq!(liq_pool.token, 0);
+        assert_eq!(liq_pool.st_token, 0);
+        assert_eq!(liq_pool.lp_token_supply, 0);
+    }
+
+    #[test]
+    fn test_getters() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(liq_pool.token_reserve(), 0);
+        assert_eq!(liq_pool.lp_token_supply(), 0);
+        liq_pool.add_liquidity(500 * UNIT).unwrap();
+        liq_pool.st_token = 100 * UNIT;
+        assert_eq!(liq_pool.token_reserve(), 500 * UNIT);
+        assert_eq!(liq_pool.st_token_reserve(), 100 * UNIT);
+        assert_eq!(liq_pool.lp_token_supply(), 500 * UNIT);
+        assert_eq!(liq_pool.max_fee(), 3 * UNIT / 100);
+        assert_eq!(liq_pool.min_fee(), 3 * UNIT / 1000);
+        assert_eq!(liq_pool.liq_target(), 100000 * UNIT);
+        assert_eq!(liq_pool.price(), 2 * UNIT);
+    }
+
+    #[test]
+    fn test_clone_is_independent() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(500 * UNIT).unwrap();
+        let checkpoint = liq_pool.clone();
+        let mut swapped = liq_pool.clone();
+        swapped.swap(10 * UNIT).unwrap();
+        assert_ne!(swapped, liq_pool);
+        assert_eq!(liq_pool, checkpoint);
+    }
+
+    #[test]
+    fn test_default() {
+        let liq_pool = LiqPool::default();
+        assert_eq!(liq_pool.max_fee, 3 * UNIT / 100);
+        assert_eq!(liq_pool.min_fee, 3 * UNIT / 1000);
+        assert_eq!(liq_pool.liq_target, 100000 * UNIT);
+        assert_eq!(liq_pool.price, UNIT);
+        assert_eq!(
+            (liq_pool.token, liq_pool.st_token, liq_pool.lp_token_supply),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_new_funded() {
+        let (liq_pool, lp_token_amount) = LiqPool::new_funded(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            500 * UNIT,
+        )
+        .unwrap();
+        assert_eq!(lp_token_amount, 500 * UNIT);
+        assert_eq!(liq_pool.token, 500 * UNIT);
+        assert_eq!(liq_pool.st_token, 0);
+        assert_eq!(liq_pool.lp_token_supply, 500 * UNIT);
+        assert!(matches!(
+            LiqPool::new_funded(
+                3 * UNIT / 1000,
+                3 * UNIT / 100,
+                100000 * UNIT,
+                2 * UNIT,
+                500 * UNIT
+            ),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_state() {
+        let liq_pool = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            500 * UNIT,
+            100 * UNIT,
+            600 * UNIT,
+        )
+        .unwrap();
+        assert_eq!(liq_pool.token, 500 * UNIT);
+        assert_eq!(liq_pool.st_token, 100 * UNIT);
+        assert_eq!(liq_pool.lp_token_supply, 600 * UNIT);
+        assert!(
+            LiqPool::from_state(3 * UNIT / 100, 3 * UNIT / 1000, 0, 2 * UNIT, 0, 0, 0).is_err()
+        );
+        assert!(LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            500 * UNIT,
+            0,
+            0
+        )
+        .is_err());
+        assert!(LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            0,
+            0,
+            600 * UNIT
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_with_reserves() {
+        let (max_fee, min_fee) = (3 * UNIT / 100, 3 * UNIT / 1000);
+        let liq_pool = LiqPool::with_reserves(
+            max_fee,
+            min_fee,
+            100000 * UNIT,
+            500 * UNIT,
+            100 * UNIT,
+            600 * UNIT,
+        )
+        .unwrap();
+        assert_eq!(liq_pool.token_reserve(), 500 * UNIT);
+        assert_eq!(liq_pool.st_token_reserve(), 100 * UNIT);
+        assert_eq!(liq_pool.lp_token_supply(), 600 * UNIT);
+        assert_eq!(liq_pool.price(), UNIT);
+        assert_eq!(liq_pool.lp_token_price().unwrap(), UNIT);
+        assert!(LiqPool::with_reserves(max_fee, min_fee, 100000 * UNIT, 0, 0, 0).is_ok());
+
+        for (max_fee, min_fee, liq_target) in [
+            (min_fee, max_fee, 100000 * UNIT),
+            (UNIT + 1, min_fee, 100000 * UNIT),
+            (max_fee, min_fee, 0),
+        ] {
+            assert!(matches!(
+                LiqPool::with_reserves(max_fee, min_fee, liq_target, 500 * UNIT, 0, 500 * UNIT),
+                Err(LiqPoolError::InvalidInputData(_))
+            ));
+        }
+        for (token, st_token, lp_token_supply) in
+            [(500 * UNIT, 0, 0), (0, 100 * UNIT, 0), (0, 0, 600 * UNIT)]
+        {
+            assert!(matches!(
+                LiqPool::with_reserves(
+                    max_fee,
+                    min_fee,
+                    100000 * UNIT,
+                    token,
+                    st_token,
+                    lp_token_supply
+                ),
+                Err(LiqPoolError::InvalidInputData(_))
+            ));
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_display() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(2991 * UNIT / 10).unwrap();
+        assert_eq!(
+            liq_pool.to_string(),
+            "token: 299.100000000, st_token: 0.000000000, lp_token_supply: 299.100000000, \
+             min_fee: 0.003000000, max_fee: 0.030000000, liq_target: 100000.000000000"
+        );
+    }
+
+    #[test]
+    fn test_logged_operations() {
+        let mut liq_pool = get_example_lp();
+        let (lp_token_minted, event) = liq_pool.add_liquidity_logged(100030 * UNIT).unwrap();
+        assert_eq!(
+            event,
+            PoolEvent::AddLiquidity {
+                token_in: 100030 * UNIT,
+                lp_token_minted
+            }
+        );
+
+        let before = liq_pool.clone();
+        let (token_out, event) = liq_pool.swap_logged(4515 * UNIT).unwrap();
+        assert_eq!(token_out, 8980967100000);
+        assert_eq!(
+            event,
+            PoolEvent::Swap {
+                st_token_in: 4515 * UNIT,
+                token_out,
+                fee: 49032900000
+            }
+        );
+        assert_eq!(before.token - liq_pool.token, token_out);
+        assert_eq!(liq_pool.st_token - before.st_token, 4515 * UNIT);
+        assert_eq!(liq_pool.collected_fees - before.collected_fees, 49032900000);
+
+        let ((token_out, st_token_out), event) =
+            liq_pool.remove_liquidity_logged(lp_token_minted).unwrap();
+        assert_eq!(
+            event,
+            PoolEvent::RemoveLiquidity {
+                lp_token_burned: lp_token_minted,
+                token_out,
+                st_token_out
+            }
+        );
+        assert_eq!((liq_pool.token, liq_pool.st_token), (0, 0));
+    }
+
+    #[test]
+    fn test_simulate() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let before = liq_pool.clone();
+        let (simulated, out) = liq_pool.simulate(|liq_pool| {
+            let first = liq_pool.swap(4515 * UNIT)?;
+            let second = liq_pool.swap(45 * UNIT)?;
+            Ok::<_, LiqPoolError>(first + second)
+        });
+        assert_eq!(liq_pool, before);
+
+        let mut expected = before.clone();
+        let first = expected.swap(4515 * UNIT).unwrap();
+        let second = expected.swap(45 * UNIT).unwrap();
+        assert_eq!(out.unwrap(), first + second);
+        assert_eq!(simulated, expected);
+        assert_eq!(simulated.st_token, 4560 * UNIT);
+    }
+
+    #[test]
+    fn test_first_deposit_into_pool_holding_st_token() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.st_token = 100 * UNIT;
+        // 100 st_token are worth 200 token
+        assert_eq!(liq_pool.add_liquidity(300 * UNIT).unwrap(), 500 * UNIT);
+        assert_eq!(liq_pool.lp_token_price().unwrap(), UNIT);
+        assert_eq!(
+            liq_pool.remove_liquidity(250 * UNIT).unwrap(),
+            (150 * UNIT, 50 * UNIT)
+        );
+    }
+
+    #[test]
+    fn test_max_total_value() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.set_max_total_value(Some(1000 * UNIT));
+        liq_pool.add_liquidity(600 * UNIT).unwrap();
+        // 250 st_token are worth 500 token
+        assert!(matches!(
+            liq_pool.add_liquidity_st(250 * UNIT),
+            Err(LiqPoolError::CapExceeded)
+        ));
+        assert!(matches!(
+            liq_pool.add_liquidity(400 * UNIT + 1),
+            Err(LiqPoolError::CapExceeded)
+        ));
+        assert_eq!(liq_pool.token, 600 * UNIT);
+        liq_pool.add_liquidity(400 * UNIT).unwrap();
+        assert_eq!(liq_pool.total_value(), 1000 * UNIT);
+
+        liq_pool.set_max_total_value(Some(1100 * UNIT));
+        let (accepted, lp_token_amount) = liq_pool.add_liquidity_up_to_cap(300 * UNIT).unwrap();
+        assert_eq!((accepted, lp_token_amount), (100 * UNIT, 100 * UNIT));
+        assert_eq!(
+            liq_pool.add_liquidity_up_to_cap(300 * UNIT).unwrap(),
+            (0, 0)
+        );
+
+        liq_pool.set_max_total_value(None);
+        liq_pool.add_liquidity(300 * UNIT).unwrap();
+        assert_eq!(liq_pool.total_value(), 1400 * UNIT);
+    }
+
+    #[test]
+    fn test_estimate_lp_growth() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let before = liq_pool.clone();
+        // Swap pays 9030 * 0.543% = 49.0329 fee, half of it goes to the
+        // owner of half of lp tokens.
+        assert_eq!(
+            liq_pool
+                .estimate_lp_growth(50015 * UNIT, &[4515 * UNIT])
+                .unwrap(),
+            24516450000
+        );
+        assert_eq!(liq_pool, before);
+        assert_eq!(liq_pool.estimate_lp_growth(50015 * UNIT, &[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        let other = liq_pool.clone();
+        let lp_token_price = liq_pool.lp_token_price().unwrap();
+        let claim = liq_pool.lp_to_underlying(100 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.merge(other.clone()).unwrap(),
+            other.lp_token_supply
+        );
+        assert_eq!(liq_pool.token, 2 * other.token);
+        assert_eq!(liq_pool.st_token, 2 * other.st_token);
+        assert_eq!(liq_pool.lp_token_supply, 2 * other.lp_token_supply);
+        assert_eq!(liq_pool.lp_token_price().unwrap(), lp_token_price);
+        assert_eq!(liq_pool.lp_to_underlying(100 * UNIT).unwrap(), claim);
+        assert_eq!(liq_pool.collected_fees(), 2 * other.collected_fees());
+        assert_eq!(liq_pool.swap_count(), 2);
+        assert_eq!(liq_pool.total_volume(), 2 * 4515 * UNIT);
+
+        let mut different_target =
+            LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 500 * UNIT, 2 * UNIT).unwrap();
+        different_target.add_liquidity(100 * UNIT).unwrap();
+        assert!(matches!(
+            liq_pool.merge(different_target),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        let mut different_price = get_example_lp();
+        different_price.update_price(3 * UNIT).unwrap();
+        different_price.add_liquidity(100 * UNIT).unwrap();
+        assert!(matches!(
+            liq_pool.merge(different_price),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_merge_pools_with_different_lp_token_price() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        let value_before = liq_pool.total_value();
+        let lp_token_price = liq_pool.lp_token_price().unwrap();
+        let mut other = get_example_lp();
+        other.add_liquidity(1000 * UNIT).unwrap();
+        other.swap(45 * UNIT).unwrap();
+        let other_value = other.total_value();
+        let other_supply = other.lp_token_supply;
+
+        let lp_token_amount = liq_pool.merge(other).unwrap();
+        assert_eq!(
+            lp_token_amount,
+            shares(other_value, value_before, 100030 * UNIT).unwrap()
+        );
+        assert_eq!(liq_pool.total_value(), value_before + other_value);
+        // Rounding of the mint is in favor of lp tokens already in the pool.
+        assert!(liq_pool.lp_token_price().unwrap() >= lp_token_price);
+        let (token, st_token) = liq_pool.lp_to_underlying(lp_token_amount).unwrap();
+        let claim = token + value(st_token, liq_pool.price).unwrap();
+        assert!(claim <= other_value && other_value - claim <= 2);
+        // Lp tokens of `other` earned more fees, so each of them is
+        // converted into more than one.
+        assert!(lp_token_amount > other_supply);
+    }
+
+    #[test]
+    fn test_merge_respects_cap_and_counters() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.set_max_total_value(Some(1000 * UNIT));
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        liq_pool.swap(45 * UNIT).unwrap();
+        let mut other = liq_pool.clone();
+        other.set_max_total_value(None);
+        assert!(matches!(
+            liq_pool.merge(other.clone()),
+            Err(LiqPoolError::CapExceeded)
+        ));
+
+        liq_pool.set_max_total_value(None);
+        let fees = liq_pool.collected_fees();
+        liq_pool.merge(other).unwrap();
+        assert_eq!(liq_pool.lp_principal(), 2000 * UNIT);
+        assert_eq!(liq_pool.withdrawable_fees().unwrap(), 2 * fees);
+        liq_pool.withdraw_collected_fees(2 * fees).unwrap();
+
+        let mut drained = get_example_lp();
+        drained.add_liquidity(100 * UNIT).unwrap();
+        drained.swap(51 * UNIT).unwrap();
+        assert!(drained.has_touched_max_fee());
+        assert!(!liq_pool.has_touched_max_fee());
+        liq_pool.merge(drained).unwrap();
+        assert!(liq_pool.has_touched_max_fee());
+    }
+
+    #[test]
+    fn test_checkpoint_and_restore() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let before = liq_pool.clone();
+        let checkpoint = liq_pool.checkpoint();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        liq_pool.remove_liquidity(30 * UNIT).unwrap();
+        assert_ne!(liq_pool, before);
+        liq_pool.restore(checkpoint);
+        assert_eq!(liq_pool, before);
+    }
+
+    #[test]
+    fn test_invariants_hold_after_operations() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        liq_pool.add_liquidity_st(1000 * UNIT).unwrap();
+        liq_pool.swap(45 * UNIT).unwrap();
+        liq_pool.remove_liquidity(50000 * UNIT).unwrap();
+        liq_pool.remove_liquidity(liq_pool.lp_token_supply).unwrap();
+        liq_pool.assert_invariants();
+        assert_eq!((liq_pool.token, liq_pool.st_token), (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "lp token supply is inconsistent with reserves")]
+    #[cfg(debug_assertions)]
+    fn test_invariants_catch_inconsistent_supply() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        liq_pool.token = 0;
+        liq_pool.assert_invariants();
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_process_swaps() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let result = liq_pool.process_swaps(&[4515 * UNIT, 45 * UNIT]).unwrap();
+        assert_eq!(result.outputs.len(), 2);
+        assert_eq!(result.outputs[0], 8980967100000);
+        assert_eq!(
+            result.total_token_out,
+            result.outputs[0] + result.outputs[1]
+        );
+        assert_eq!(result.total_fee, 9120 * UNIT - result.total_token_out);
+        assert_eq!(result.failed_at, None);
+        assert_eq!(liq_pool.token, 100030 * UNIT - result.total_token_out);
+
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        let result = liq_pool
+            .process_swaps(&[200 * UNIT, 200 * UNIT, 200 * UNIT, 10 * UNIT])
+            .unwrap();
+        assert_eq!(result.outputs.len(), 2);
+        assert_eq!(result.failed_at, Some(2));
+        assert_eq!(liq_pool.st_token, 400 * UNIT);
+        assert_eq!(liq_pool.token, 1000 * UNIT - result.total_token_out);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_swap_iter() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        let mut expected = liq_pool.clone();
+        let outputs: Vec<_> = liq_pool
+            .swap_iter([100 * UNIT, 500 * UNIT, 10 * UNIT].into_iter())
+            .collect();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(
+            *outputs[0].as_ref().unwrap(),
+            expected.swap(100 * UNIT).unwrap()
+        );
+        assert!(outputs[1].is_err());
+        assert_eq!(
+            *outputs[2].as_ref().unwrap(),
+            expected.swap(10 * UNIT).unwrap()
+        );
+        assert_eq!(liq_pool, expected);
+    }
+
+    #[test]
+    fn test_swap_iter_stopped_early() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        let mut expected = liq_pool.clone();
+        let pulled = liq_pool
+            .swap_iter((1..).map(|i| i * 10 * UNIT))
+            .take_while(|result| result.is_ok())
+            .count();
+        // 10 + 20 + ... + 100 st_token is worth 1100 token, more than in the
+        // pool, so the 10th swap fails.
+        assert_eq!(pulled, 9);
+        for i in 1..=9 {
+            expected.swap(i * 10 * UNIT).unwrap();
+        }
+        assert_eq!(liq_pool, expected);
+
+        let mut untouched = expected.clone();
+        drop(untouched.swap_iter([10 * UNIT].into_iter()));
+        assert_eq!(untouched, expected);
+    }
+
+    #[test]
+    fn test_from_state_rejects_inconsistent_state() {
+        // fees in wrong order
+        assert!(LiqPool::from_state(
+            3 * UNIT / 1000,
+            3 * UNIT / 100,
+            100000 * UNIT,
+            2 * UNIT,
+            500 * UNIT,
+            100 * UNIT,
+            600 * UNIT
+        )
+        .is_err());
+        // only st_token in reserves and no lp tokens
+        assert!(LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            0,
+            100 * UNIT,
+            0
+        )
+        .is_err());
+        // drained pool with every lp token burned is valid
+        let liq_pool = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            0,
+            0,
+            0,
+        )
+        .unwrap();
+        assert_eq!(liq_pool, get_example_lp());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(500 * UNIT).unwrap();
+        liq_pool.swap(10 * UNIT).unwrap();
+        let json = serde_json::to_string(&liq_pool).unwrap();
+        let deserialized: LiqPool = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, liq_pool);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_rejects_invalid_state() {
+        let mut liq_pool = get_example_lp();
+        liq_pool
+            .apply_op_with_nonce(Op::AddLiquidity(500 * UNIT), 1)
+            .unwrap();
+        let json = serde_json::to_value(&liq_pool).unwrap();
+        for (field, invalid) in [
+            ("min_fee", serde_json::json!(UNIT)),
+            ("max_fee", serde_json::json!(UNIT + 1)),
+            ("liq_target", serde_json::json!(0)),
+            ("price", serde_json::json!(0)),
+            ("lp_token_supply", serde_json::json!(0)),
+            ("nonce_window", serde_json::json!(0)),
+        ] {
+            let mut json = json.clone();
+            json[field] = invalid;
+            assert!(
+                serde_json::from_value::<LiqPool>(json).is_err(),
+                "accepted invalid {field}"
+            );
+        }
+
+        let mut json = serde_json::to_value(get_example_lp()).unwrap();
+        json["lp_principal"] = serde_json::json!(UNIT);
+        assert!(serde_json::from_value::<LiqPool>(json).is_err());
+    }
+
+    #[test]
+    fn test_total_value_and_lp_token_price_of_empty_pool() {
+        let liq_pool = get_example_lp();
+        assert_eq!(liq_pool.total_value(), 0);
+        assert_eq!(liq_pool.lp_token_price().unwrap(), UNIT);
+    }
+
+    #[test]
+    fn test_total_value_and_lp_token_price_with_fees() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        assert_eq!(liq_pool.lp_token_price().unwrap(), UNIT);
+        liq_pool.swap(45 * UNIT).unwrap();
+        assert_eq!(liq_pool.total_value(), 581250 * UNIT + 27 * UNIT / 100);
+        assert!(liq_pool.lp_token_price().unwrap() > UNIT);
+
+        liq_pool.st_token = u64::MAX;
+        assert_eq!(liq_pool.total_value(), u64::MAX);
+    }
+
+    #[test]
+    fn test_removing_liquidity_by_fraction() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 500 * UNIT;
+        liq_pool.st_token = 100 * UNIT;
+        liq_pool.lp_token_supply = 600 * UNIT;
+
+        let whole = liq_pool.clone().remove_liquidity(600 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool
+                .clone()
+                .remove_liquidity_by_fraction(600 * UNIT, 1, 1)
+                .unwrap(),
+            whole
+        );
+
+        let (token_amount, st_token_amount) = liq_pool
+            .remove_liquidity_by_fraction(600 * UNIT, 1, 3)
+            .unwrap();
+        assert_eq!(token_amount, 500 * UNIT / 3);
+        assert_eq!(st_token_amount, 100 * UNIT / 3);
+        assert_eq!(liq_pool.lp_token_supply, 400 * UNIT);
+    }
+
+    // Tests based on examples in marinade docs
+    // https://docs.marinade.finance/marinade-protocol/system-overview/unstake-liquidity-pool
+
+    #[test]
+    fn test_linear_fee_with_target_reached() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        assert_eq!(liq_pool.linear_fee(90 * UNIT).unwrap(), 3 * UNIT / 1000);
+    }
+
+    #[test]
+    fn test_linear_fee_with_target_not_reached() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.linear_fee(9030 * UNIT).unwrap(),
+            543 * UNIT / 100000
+        );
+    }
+
+    #[test]
+    fn test_current_fee() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        // 4515 st_token are worth 9030 token
+        assert_eq!(
+            liq_pool.current_fee(4515 * UNIT).unwrap(),
+            543 * UNIT / 100000
+        );
+        assert_eq!(
+            liq_pool.current_fee(4515 * UNIT).unwrap(),
+            liq_pool.linear_fee(9030 * UNIT).unwrap()
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_fee_curve_samples() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(150000 * UNIT).unwrap();
+        let samples = liq_pool.fee_curve_samples(100000 * UNIT, 11).unwrap();
+        assert_eq!(samples.len(), 11);
+        assert_eq!(samples[0], (0, liq_pool.min_fee));
+        assert_eq!(samples[10], (100000 * UNIT, liq_pool.max_fee));
+        for pair in samples.windows(2) {
+            assert_eq!(pair[1].0 - pair[0].0, 10000 * UNIT);
+            assert!(pair[1].1 >= pair[0].1);
+        }
+        assert!(samples[5].1 > liq_pool.min_fee);
+        assert!(liq_pool
+            .fee_curve_samples(100000 * UNIT, 0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_swapping_matches_docs_formula() {
+        // 0.543% fee from the docs example
+        assert_eq!(
+            expected_swap_out(4515 * UNIT, 2 * UNIT, 543 * UNIT / 100000),
+            8980967100000.0
+        );
+        for init_liq in [1000 * UNIT, 100030 * UNIT, 581250 * UNIT] {
+            for st_token_amount in [1, 999, UNIT, 45 * UNIT, 123456789 * 1000, 4515 * UNIT] {
+                let mut liq_pool = get_example_lp();
+                liq_pool.add_liquidity(init_liq).unwrap();
+                let fee = liq_pool.current_fee(st_token_amount).unwrap();
+                let Ok(token_out) = liq_pool.swap(st_token_amount) else {
+                    continue;
+                };
+                let expected = expected_swap_out(st_token_amount, liq_pool.price, fee);
+                assert!(
+                    (token_out as f64 - expected).abs() <= 1.0,
+                    "{token_out} is not {expected}"
+                );
+            }
+        }
+    }
+
+    fn generic_swapping_test(init_liq: u64, swap_amount: u64, expected_token_amount: u64) {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(init_liq).unwrap();
+        let token_amount = liq_pool.swap(swap_amount).unwrap();
+        assert_eq!(token_amount, expected_token_amount);
+        assert_eq!(liq_pool.token, init_liq - token_amount);
+        assert_eq!(liq_pool.st_token, swap_amount);
+        assert_eq!(liq_pool.lp_token_supply, init_liq);
+    }
+
+    #[test]
+    fn test_swapping_with_target_reached() {
+        generic_swapping_test(581250 * UNIT, 45 * UNIT, 8973 * UNIT / 100);
+    }
+
+    #[test]
+    fn test_swapping_with_target_not_reached() {
         generic_swapping_test(100030 * UNIT, 4515 * UNIT, 8980967100000);
     }
 
+    #[test]
+    fn test_fee_after_pending_deposit() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let (fee_now, fee_after) = liq_pool
+            .fee_after_pending_deposit(4515 * UNIT, 100 * UNIT)
+            .unwrap();
+        assert_eq!(fee_now, 543 * UNIT / 100000);
+        assert!(fee_after < fee_now);
+        assert_eq!(liq_pool.token, 100030 * UNIT);
+        // Deposit big enough to keep liquidity above target after swap.
+        let (_, fee_after) = liq_pool
+            .fee_after_pending_deposit(4515 * UNIT, 10000 * UNIT)
+            .unwrap();
+        assert_eq!(fee_after, 3 * UNIT / 1000);
+
+        assert_eq!(
+            liq_pool.fee_after_pending_deposit(4515 * UNIT, 0).unwrap(),
+            (fee_now, fee_now)
+        );
+        // Pending deposit is not checked against the cap.
+        liq_pool.set_max_total_value(Some(100030 * UNIT));
+        let (_, fee_after) = liq_pool
+            .fee_after_pending_deposit(4515 * UNIT, 10000 * UNIT)
+            .unwrap();
+        assert_eq!(fee_after, 3 * UNIT / 1000);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_apply_op_with_nonce() {
+        let mut liq_pool = get_example_lp();
+        liq_pool
+            .apply_op_with_nonce(Op::AddLiquidity(500 * UNIT), 1)
+            .unwrap();
+        liq_pool
+            .apply_op_with_nonce(Op::Swap(10 * UNIT), 2)
+            .unwrap();
+        let token = liq_pool.token;
+        assert!(matches!(
+            liq_pool.apply_op_with_nonce(Op::Swap(10 * UNIT), 2),
+            Err(LiqPoolError::DuplicateOperation(2))
+        ));
+        assert_eq!(liq_pool.token, token);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_nonce_window() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.set_nonce_window(2);
+        liq_pool
+            .apply_op_with_nonce(Op::AddLiquidity(500 * UNIT), 1)
+            .unwrap();
+        liq_pool
+            .apply_op_with_nonce(Op::Swap(10 * UNIT), 2)
+            .unwrap();
+        liq_pool
+            .apply_op_with_nonce(Op::Swap(10 * UNIT), 3)
+            .unwrap();
+        // Nonce 1 fell out of the window.
+        liq_pool
+            .apply_op_with_nonce(Op::Swap(10 * UNIT), 1)
+            .unwrap();
+        assert!(liq_pool
+            .apply_op_with_nonce(Op::Swap(10 * UNIT), 3)
+            .is_err());
+    }
+
+    #[test]
+    fn test_deposit_to_keep_swap_cheap() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let deposit = liq_pool.deposit_to_keep_swap_cheap(4515 * UNIT).unwrap();
+        assert_eq!(deposit, 9000 * UNIT);
+        liq_pool.add_liquidity(deposit).unwrap();
+        assert_eq!(liq_pool.linear_fee(9030 * UNIT).unwrap(), liq_pool.min_fee);
+        assert_eq!(liq_pool.deposit_to_keep_swap_cheap(4515 * UNIT).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_st_token_exposure_bps() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(liq_pool.st_token_exposure_bps().unwrap(), 0);
+        liq_pool.token = 600 * UNIT;
+        liq_pool.st_token = 200 * UNIT;
+        // st_token is worth 400 out of total 1000.
+        assert_eq!(liq_pool.st_token_exposure_bps().unwrap(), 4000);
+    }
+
+    #[test]
+    fn test_price_shock_impact() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 600 * UNIT;
+        liq_pool.st_token = 200 * UNIT;
+        assert_eq!(
+            liq_pool.price_shock_impact(UNIT).unwrap(),
+            -200 * UNIT as i128
+        );
+        assert_eq!(
+            liq_pool.price_shock_impact(3 * UNIT).unwrap(),
+            200 * UNIT as i128
+        );
+        assert_eq!(liq_pool.price, 2 * UNIT);
+    }
+
+    #[test]
+    fn test_max_drawdown() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        let ops = [
+            Op::Swap(45 * UNIT),
+            Op::AddLiquidity(100 * UNIT),
+            Op::Swap(45 * UNIT),
+        ];
+        let drawdown = liq_pool.max_drawdown(&ops).unwrap();
+        // Deepest point is right after the first swap.
+        assert_eq!(drawdown, 8973 * UNIT / 100);
+        assert_eq!(liq_pool.token, 581250 * UNIT);
+        assert_eq!(liq_pool.max_drawdown(&[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rounding_loss_on_swap() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        // Price of 2 makes both swapped value and fee exact.
+        assert_eq!(liq_pool.rounding_loss_on_swap(45 * UNIT).unwrap(), 0);
+
+        let mut liq_pool = LiqPool::new(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            19 * UNIT / 10,
+        )
+        .unwrap();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        // 1 st_token unit is worth 1.9 token units, but it is rounded down
+        // to 1 and fee on it is rounded up to 1, so swapper gets nothing.
+        assert_eq!(liq_pool.rounding_loss_on_swap(1).unwrap(), 2);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_infer_params() {
+        let observe = |token_reserve: u64, st_token_in: u64| {
+            let mut liq_pool = get_example_lp();
+            liq_pool.add_liquidity(token_reserve).unwrap();
+            let token_out = liq_pool.swap(st_token_in).unwrap();
+            (st_token_in, token_out, token_reserve)
+        };
+        let observations = [
+            observe(200000 * UNIT, 1000 * UNIT),
+            observe(52000 * UNIT, 1000 * UNIT),
+            observe(22000 * UNIT, 1000 * UNIT),
+        ];
+        assert_eq!(
+            LiqPool::infer_params(2 * UNIT, &observations).unwrap(),
+            (3 * UNIT / 1000, 3 * UNIT / 100, 100000 * UNIT)
+        );
+        // Both regimes have to be present.
+        assert!(LiqPool::infer_params(2 * UNIT, &observations[..2]).is_err());
+        assert!(LiqPool::infer_params(2 * UNIT, &observations[1..]).is_err());
+    }
+
+    #[test]
+    fn test_seed_reserves_for_price() {
+        assert_eq!(
+            LiqPool::seed_reserves_for_price(105 * UNIT / 100, 1000 * UNIT).unwrap(),
+            1050 * UNIT
+        );
+        assert_eq!(LiqPool::seed_reserves_for_price(UNIT, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_swaps_until_max_fee() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(101000 * UNIT).unwrap();
+        let count = liq_pool.swaps_until_max_fee(100 * UNIT).unwrap();
+        assert_eq!(count, 5);
+        for _ in 0..count {
+            assert_eq!(liq_pool.linear_fee(200 * UNIT).unwrap(), liq_pool.min_fee);
+            liq_pool.swap(100 * UNIT).unwrap();
+        }
+        assert!(liq_pool.linear_fee(200 * UNIT).unwrap() > liq_pool.min_fee);
+        assert_eq!(liq_pool.swaps_until_max_fee(100 * UNIT).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_current_deposit_ratio() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(liq_pool.current_deposit_ratio().unwrap(), (UNIT, 0));
+        liq_pool.token = 600 * UNIT;
+        liq_pool.st_token = 200 * UNIT;
+        assert_eq!(
+            liq_pool.current_deposit_ratio().unwrap(),
+            (6 * UNIT / 10, 4 * UNIT / 10)
+        );
+    }
+
+    #[test]
+    fn test_swap_fee_capped() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let mut capped_pool = liq_pool.clone();
+        // Cap above curve fee changes nothing.
+        assert_eq!(
+            liq_pool.clone().swap_fee_capped(4515 * UNIT, UNIT).unwrap(),
+            8980967100000
+        );
+        let token_amount = capped_pool
+            .swap_fee_capped(4515 * UNIT, 4 * UNIT / 1000)
+            .unwrap();
+        assert_eq!(token_amount, 8993880000000);
+        assert!(token_amount > liq_pool.swap(4515 * UNIT).unwrap());
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_fingerprint() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(
+            liq_pool.fingerprint(),
+            "token=0.000000000;st_token=0.000000000;lp=0.000000000;price_per_share=1.000000000"
+        );
+        liq_pool.token = 500 * UNIT;
+        liq_pool.st_token = 25 * UNIT / 100;
+        liq_pool.lp_token_supply = 400 * UNIT;
+        assert_eq!(
+            liq_pool.fingerprint(),
+            "token=500.000000000;st_token=0.250000000;lp=400.000000000;price_per_share=1.251250000"
+        );
+        liq_pool.token = u64::MAX;
+        liq_pool.lp_token_supply = 1;
+        assert!(liq_pool
+            .fingerprint()
+            .ends_with("price_per_share=18446744073.709551615"));
+    }
+
+    #[test]
+    fn test_fee_equalizing_split() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let (first, second) = liq_pool.fee_equalizing_split(4515 * UNIT).unwrap();
+        assert_eq!(first + second, 4515 * UNIT);
+        let first_fee = liq_pool
+            .linear_fee(value(first, liq_pool.price).unwrap())
+            .unwrap();
+        liq_pool.swap(first).unwrap();
+        let second_fee = liq_pool
+            .linear_fee(value(second, liq_pool.price).unwrap())
+            .unwrap();
+        assert!(first_fee.abs_diff(second_fee) <= 1);
+
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.fee_equalizing_split(45 * UNIT).unwrap(),
+            (45 * UNIT / 2, 45 * UNIT / 2)
+        );
+    }
+
+    #[test]
+    fn test_expected_fee() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        // 15 st_token leaves exactly liq_target and pays min fee,
+        // 4515 st_token pays 0.543%.
+        assert_eq!(
+            liq_pool.expected_fee(&[15 * UNIT, 4515 * UNIT]).unwrap(),
+            (30 * 3 * UNIT / 1000 + 9030 * 543 * UNIT / 100000) / 9060
+        );
+        assert_eq!(
+            liq_pool.expected_fee(&[15 * UNIT]).unwrap(),
+            3 * UNIT / 1000
+        );
+        assert!(liq_pool.expected_fee(&[]).is_err());
+    }
+
+    #[test]
+    fn test_settle_st_token() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 500 * UNIT;
+        liq_pool.st_token = 100 * UNIT;
+        liq_pool.lp_token_supply = 700 * UNIT;
+        assert_eq!(liq_pool.settle_st_token(40 * UNIT).unwrap(), 80 * UNIT);
+        assert_eq!(liq_pool.token, 580 * UNIT);
+        assert_eq!(liq_pool.st_token, 60 * UNIT);
+        assert!(liq_pool.settle_st_token(61 * UNIT).is_err());
+    }
+
+    #[test]
+    fn test_rebalance() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let token_out = liq_pool.swap(4515 * UNIT).unwrap();
+        assert_eq!(liq_pool.token, 100030 * UNIT - token_out);
+        // Unstake was paid out after price has grown to 2.01.
+        liq_pool
+            .rebalance(4515 * UNIT, 907515 * UNIT / 100)
+            .unwrap();
+        assert_eq!(liq_pool.st_token, 0);
+        assert_eq!(
+            liq_pool.token,
+            100030 * UNIT - token_out + 907515 * UNIT / 100
+        );
+        assert!(liq_pool.token > 100030 * UNIT);
+        assert!(matches!(
+            liq_pool.rebalance(1, 0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_rebalance_cannot_empty_pool_with_lp_outstanding() {
+        let mut liq_pool = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            UNIT,
+            0,
+            100 * UNIT,
+            100 * UNIT,
+        )
+        .unwrap();
+        assert!(matches!(
+            liq_pool.rebalance(100 * UNIT, 0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert_eq!(liq_pool.st_token, 100 * UNIT);
+        assert_eq!(liq_pool.token, 0);
+        liq_pool.rebalance(100 * UNIT, 99 * UNIT).unwrap();
+        assert_eq!(liq_pool.token, 99 * UNIT);
+        assert_eq!(liq_pool.st_token, 0);
+    }
+
+    #[test]
+    fn test_reserves_ratio() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(liq_pool.reserves_ratio().unwrap(), UNIT);
+        liq_pool.add_liquidity(200000 * UNIT).unwrap();
+        assert_eq!(liq_pool.reserves_ratio().unwrap(), UNIT);
+        assert!(!liq_pool.is_liquidity_depleted());
+
+        liq_pool.swap(50000 * UNIT).unwrap();
+        let ratio_after_first_swap = liq_pool.reserves_ratio().unwrap();
+        // 100300 token out of 200300 total value
+        assert_eq!(ratio_after_first_swap, 500748876);
+        assert!(!liq_pool.is_liquidity_depleted());
+        liq_pool.swap(40000 * UNIT).unwrap();
+        assert!(liq_pool.reserves_ratio().unwrap() < ratio_after_first_swap / 2);
+        assert!(liq_pool.is_liquidity_depleted());
+    }
+
+    #[test]
+    fn test_health_factor() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(liq_pool.health_factor(), 0);
+        liq_pool.add_liquidity(150000 * UNIT).unwrap();
+        assert_eq!(liq_pool.health_factor(), UNIT);
+        liq_pool.swap(20000 * UNIT).unwrap();
+        assert_eq!(liq_pool.health_factor(), UNIT);
+        let half_depleted = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            UNIT,
+            50000 * UNIT,
+            0,
+            50000 * UNIT,
+        )
+        .unwrap();
+        assert_eq!(half_depleted.health_factor(), UNIT / 2);
+    }
+
+    #[test]
+    fn test_pool_value() {
+        let mut liq_pool =
+            LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 500 * UNIT, 2 * UNIT).unwrap();
+        assert_eq!(liq_pool.pool_value().unwrap(), 0);
+        liq_pool.add_liquidity(800 * UNIT).unwrap();
+        liq_pool.swap(150 * UNIT).unwrap();
+        // 500.9 token and 150 st_token worth 300 token
+        assert_eq!(liq_pool.pool_value().unwrap(), 8009 * UNIT / 10);
+        assert_eq!(liq_pool.total_value(), 8009 * UNIT / 10);
+        // Deposit is priced against the whole value, 400 * 800 / 800.9
+        assert_eq!(liq_pool.add_liquidity(400 * UNIT).unwrap(), 399550505681);
+        // Withdrawal pays out share of each reserve.
+        assert_eq!(
+            liq_pool.remove_liquidity(600 * UNIT).unwrap(),
+            (450618792155, 75028103922)
+        );
+        // Fee depends on token only, remaining st_token does not
+        // count towards liquidity target.
+        let mut token_only =
+            LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 500 * UNIT, 2 * UNIT).unwrap();
+        token_only.add_liquidity(liq_pool.token).unwrap();
+        assert_eq!(
+            liq_pool.linear_fee(100 * UNIT).unwrap(),
+            token_only.linear_fee(100 * UNIT).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_average_fee_for_split() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let before = liq_pool.clone();
+        let single = liq_pool.average_fee_for_split(4515 * UNIT, 1).unwrap();
+        assert_eq!(single, 543 * UNIT / 100000);
+        // First of two chunks leaves 95515 token and pays 3% - 2.7% *
+        // 0.95515 = 0.421095%. Second one leaves 95534.01243925 - 4515
+        // token and pays 0.5424867%, a little less than the whole swap.
+        assert_eq!(
+            liq_pool.average_fee_for_split(4515 * UNIT, 2).unwrap(),
+            (4210950 + 5424867) / 2
+        );
+        let split = liq_pool.average_fee_for_split(4515 * UNIT, 10).unwrap();
+        assert!(split < single);
+        assert_eq!(liq_pool, before);
+        assert!(liq_pool.average_fee_for_split(4515 * UNIT, 0).is_err());
+    }
+
+    #[test]
+    fn test_min_fee_threshold() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(150000 * UNIT).unwrap();
+        assert_eq!(liq_pool.min_fee_threshold(), 50000 * UNIT);
+        // 25000 st_token are worth 50000 token
+        assert_eq!(
+            liq_pool.current_fee(25000 * UNIT).unwrap(),
+            liq_pool.min_fee
+        );
+        assert!(liq_pool.current_fee(25000 * UNIT + 1).unwrap() > liq_pool.min_fee);
+
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100000 * UNIT).unwrap();
+        assert_eq!(liq_pool.min_fee_threshold(), 0);
+        liq_pool.swap(10 * UNIT).unwrap();
+        assert_eq!(liq_pool.min_fee_threshold(), 0);
+    }
+
+    #[test]
+    fn test_composition_drift() {
+        let mut liq_pool = get_example_lp();
+        let lp_token_amount = liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool
+                .composition_drift(lp_token_amount, 100030 * UNIT)
+                .unwrap(),
+            (0, 0)
+        );
+        let token_out = liq_pool.swap(4515 * UNIT).unwrap();
+        // Sole lp holder's claim lost the token paid out and got all
+        // st_token swapped in.
+        assert_eq!(
+            liq_pool
+                .composition_drift(lp_token_amount, 100030 * UNIT)
+                .unwrap(),
+            (token_out, 4515 * UNIT)
+        );
+        assert_eq!(
+            liq_pool
+                .composition_drift(lp_token_amount / 2, 50015 * UNIT)
+                .unwrap(),
+            (token_out / 2, 4515 * UNIT / 2)
+        );
+    }
+
+    #[test]
+    fn test_swap_then_settle_residual() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        // Swapper got 89.73 out of 90, 0.27 stays in the pool.
+        assert_eq!(
+            liq_pool.swap_then_settle_residual(45 * UNIT).unwrap(),
+            -27 * UNIT as i128 / 100
+        );
+        assert_eq!(liq_pool.st_token, 0);
+    }
+
+    #[test]
+    fn test_marginal_output() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.marginal_output(0, 4515 * UNIT).unwrap(),
+            8980967100000
+        );
+        let marginal = liq_pool.marginal_output(1000 * UNIT, 4515 * UNIT).unwrap();
+        assert!(marginal < 8980967100000);
+        liq_pool.swap(1000 * UNIT).unwrap();
+        assert_eq!(liq_pool.swap(4515 * UNIT).unwrap(), marginal);
+    }
+
+    #[test]
+    fn test_fee_as_equivalent_lp() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 500 * UNIT;
+        liq_pool.st_token = 100 * UNIT;
+        liq_pool.lp_token_supply = 350 * UNIT;
+        // One lp token is worth 700 / 350 = 2 token.
+        assert_eq!(liq_pool.fee_as_equivalent_lp(10 * UNIT).unwrap(), 5 * UNIT);
+    }
+
+    #[test]
+    fn test_advance_epoch() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        let result = liq_pool
+            .advance_epoch(&[45 * UNIT, 45 * UNIT], 21 * UNIT / 10)
+            .unwrap();
+        assert_eq!(
+            result,
+            EpochResult {
+                fee_value_gained: 54 * UNIT / 100,
+                price_value_gained: 9 * UNIT as i128,
+            }
+        );
+        assert_eq!(liq_pool.price, 21 * UNIT / 10);
+        assert_eq!(liq_pool.st_token, 90 * UNIT);
+        assert_eq!(liq_pool.current_epoch(), 1);
+
+        // Failed swap leaves pool untouched.
+        assert!(liq_pool
+            .advance_epoch(&[45 * UNIT, 1000000 * UNIT], UNIT)
+            .is_err());
+        assert_eq!(liq_pool.price, 21 * UNIT / 10);
+        assert_eq!(liq_pool.current_epoch(), 1);
+    }
+
+    #[test]
+    fn test_advance_fee_epoch() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(200000 * UNIT).unwrap();
+        assert_eq!(liq_pool.current_epoch(), 0);
+        assert_eq!(liq_pool.swap(10 * UNIT).unwrap(), 1994 * UNIT / 100);
+
+        liq_pool
+            .advance_fee_epoch(5 * UNIT / 100, UNIT / 100)
+            .unwrap();
+        assert_eq!(liq_pool.current_epoch(), 1);
+        assert_eq!(liq_pool.min_fee(), UNIT / 100);
+        assert_eq!(liq_pool.swap(10 * UNIT).unwrap(), 198 * UNIT / 10);
+
+        assert!(matches!(
+            liq_pool.advance_fee_epoch(UNIT / 100, 5 * UNIT / 100),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(liq_pool.advance_fee_epoch(UNIT + 1, 0).is_err());
+        assert_eq!(liq_pool.current_epoch(), 1);
+        assert_eq!(liq_pool.max_fee(), 5 * UNIT / 100);
+    }
+
+    #[test]
+    fn test_target_for_peak_swap() {
+        let (max_fee, min_fee, fee_ceiling) = (3 * UNIT / 100, 3 * UNIT / 1000, UNIT / 100);
+        let target =
+            LiqPool::target_for_peak_swap(1000 * UNIT, fee_ceiling, max_fee, min_fee).unwrap();
+        assert_eq!(target, 3857142857143);
+        let fee_at_target = |liq_target: u64| {
+            let mut liq_pool = LiqPool::new(max_fee, min_fee, liq_target, UNIT).unwrap();
+            liq_pool.add_liquidity(liq_target).unwrap();
+            liq_pool.linear_fee(1000 * UNIT).unwrap()
+        };
+        assert!(fee_at_target(target) <= fee_ceiling);
+        assert!(fee_at_target(target - 1) > fee_ceiling);
+
+        assert!(LiqPool::target_for_peak_swap(1000 * UNIT, min_fee, max_fee, min_fee).is_err());
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_decimal_reserves() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 505259432556;
+        liq_pool.st_token = 1;
+        liq_pool.lp_token_supply = 500 * UNIT;
+        assert_eq!(liq_pool.token_decimal(), "505.259432556");
+        assert_eq!(liq_pool.st_token_decimal(), "0.000000001");
+        assert_eq!(liq_pool.lp_supply_decimal(), "500.000000000");
+    }
+
+    #[test]
+    fn test_parity_swap() {
+        let mut a = get_example_lp();
+        a.add_liquidity(150000 * UNIT).unwrap();
+        let mut b = get_example_lp();
+        b.add_liquidity(80000 * UNIT).unwrap();
+        let (a_amount, b_amount) = parity_swap(&a, &b).unwrap();
+        assert_eq!(b_amount, 0);
+        assert!(a_amount > 0);
+        let rate_b = b.marginal_rate().unwrap();
+        let mut swapped = a.clone();
+        swapped.swap(a_amount).unwrap();
+        assert!(swapped.marginal_rate().unwrap() >= rate_b);
+        swapped.swap(1).unwrap();
+        assert!(swapped.marginal_rate().unwrap() < rate_b);
+        assert_eq!(parity_swap(&b, &a).unwrap(), (0, a_amount));
+    }
+
+    #[test]
+    fn test_vs_hold() {
+        let mut liq_pool = get_example_lp();
+        let lp_token_amount = liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.vs_hold(581250 * UNIT, 0, lp_token_amount).unwrap(),
+            0
+        );
+        liq_pool.swap(45 * UNIT).unwrap();
+        // The only LP has earned the whole fee.
+        assert_eq!(
+            liq_pool.vs_hold(581250 * UNIT, 0, lp_token_amount).unwrap(),
+            27 * UNIT as i128 / 100
+        );
+        assert!(liq_pool.vs_hold(0, 0, lp_token_amount + 1).is_err());
+    }
+
+    #[test]
+    fn test_regime_boundaries() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let (min_fee_boundary, max_fee_boundary) = liq_pool.regime_boundaries().unwrap();
+        assert_eq!(min_fee_boundary, 15 * UNIT);
+        assert_eq!(max_fee_boundary, 50015 * UNIT);
+        let fee_of = |st_token_amount: u64| {
+            liq_pool
+                .linear_fee(value(st_token_amount, liq_pool.price).unwrap())
+                .unwrap()
+        };
+        assert_eq!(fee_of(min_fee_boundary), liq_pool.min_fee);
+        assert!(fee_of(min_fee_boundary + 1) > liq_pool.min_fee);
+        assert_eq!(fee_of(max_fee_boundary), liq_pool.max_fee);
+        assert!(fee_of(max_fee_boundary / 2) < liq_pool.max_fee);
+    }
+
+    #[test]
+    fn test_preview_swap_conservative() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.preview_swap_conservative(45 * UNIT, 0).unwrap(),
+            8973 * UNIT / 100
+        );
+        assert_eq!(
+            liq_pool.preview_swap_conservative(45 * UNIT, 100).unwrap(),
+            888327 * UNIT / 10000
+        );
+        assert_eq!(liq_pool.token, 581250 * UNIT);
+        assert!(liq_pool
+            .preview_swap_conservative(45 * UNIT, 10001)
+            .is_err());
+    }
+
+    #[test]
+    fn test_has_touched_max_fee() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        liq_pool.swap(10 * UNIT).unwrap();
+        assert!(!liq_pool.has_touched_max_fee());
+        // Swap taking value of the whole token reserve is charged max fee.
+        let token = liq_pool.token;
+        liq_pool.swap(token / 2).unwrap();
+        assert!(liq_pool.has_touched_max_fee());
+        // Flag stays even after liquidity recovers.
+        liq_pool.add_liquidity(1000000 * UNIT).unwrap();
+        liq_pool.swap(10 * UNIT).unwrap();
+        assert!(liq_pool.has_touched_max_fee());
+    }
+
+    #[test]
+    fn test_fee_maximizing_deposit() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(50000 * UNIT).unwrap();
+        let swaps = [4515 * UNIT; 3];
+        let max_deposit = 200000 * UNIT;
+        let deposit = liq_pool
+            .fee_maximizing_deposit(&swaps, max_deposit)
+            .unwrap();
+        let earnings = liq_pool.deposit_fee_earnings(deposit, &swaps).unwrap();
+        assert!(deposit > 0 && deposit < max_deposit);
+        assert!(earnings > liq_pool.deposit_fee_earnings(max_deposit, &swaps).unwrap());
+        assert!(
+            earnings
+                > liq_pool
+                    .deposit_fee_earnings(max_deposit / 100, &swaps)
+                    .unwrap()
+        );
+        assert_eq!(liq_pool.token, 50000 * UNIT);
+    }
+
+    #[test]
+    fn test_relative_to() {
+        let mut reference = get_example_lp();
+        reference.token = 600 * UNIT;
+        reference.st_token = 200 * UNIT;
+        reference.lp_token_supply = 1000 * UNIT;
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 1300 * UNIT;
+        liq_pool.st_token = 100 * UNIT;
+        liq_pool.lp_token_supply = 500 * UNIT;
+        // Value 1500 vs 1000, utilization 2/15 vs 4/10.
+        assert_eq!(
+            liq_pool.relative_to(&reference).unwrap(),
+            RelativeStats {
+                total_value_ratio: Some(15 * UNIT / 10),
+                lp_supply_ratio: Some(UNIT / 2),
+                utilization_ratio: Some(333333335),
+            }
+        );
+        let empty = get_example_lp();
+        assert_eq!(
+            liq_pool.relative_to(&empty).unwrap(),
+            RelativeStats {
+                total_value_ratio: None,
+                lp_supply_ratio: None,
+                utilization_ratio: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_max_fee_for_output_guarantee() {
+        let (volume, min_output) = (1000 * UNIT, 980 * UNIT);
+        let max_fee = LiqPool::max_fee_for_output_guarantee(volume, min_output).unwrap();
+        assert_eq!(max_fee, 2 * UNIT / 100);
+        assert!(apply_fee_round_up(volume, max_fee).unwrap() >= min_output);
+        assert!(apply_fee_round_up(volume, max_fee + 1).unwrap() < min_output);
+
+        let max_fee = LiqPool::max_fee_for_output_guarantee(3, 2).unwrap();
+        assert_eq!(apply_fee_round_up(3, max_fee).unwrap(), 2);
+        assert_eq!(apply_fee_round_up(3, max_fee + 1).unwrap(), 1);
+        assert_eq!(LiqPool::max_fee_for_output_guarantee(3, 0).unwrap(), UNIT);
+        assert!(LiqPool::max_fee_for_output_guarantee(3, 4).is_err());
+    }
+
+    #[test]
+    fn test_exit_value_with_pending_fees() {
+        let mut liq_pool = get_example_lp();
+        let lp_token_amount = liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        liq_pool.swap(45 * UNIT).unwrap();
+        let (token_amount, st_token_amount) = liq_pool
+            .exit_value_with_pending_fees(lp_token_amount / 2)
+            .unwrap();
+        assert_eq!(token_amount, (581250 * UNIT - 8973 * UNIT / 100) / 2);
+        assert_eq!(st_token_amount, 45 * UNIT / 2);
+        // Half of 0.27 fee is included in exit value.
+        assert_eq!(
+            token_amount + value(st_token_amount, liq_pool.price).unwrap(),
+            581250 * UNIT / 2 + 27 * UNIT / 200
+        );
+        assert_eq!(liq_pool.lp_token_supply, lp_token_amount);
+    }
+
+    #[test]
+    fn test_sandwich_profit() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        // Above target front swap pays only min fee, 0.3% of 90.
+        assert_eq!(
+            liq_pool.sandwich_profit(45 * UNIT, 1000 * UNIT, 0).unwrap(),
+            -27 * UNIT as i128 / 100
+        );
+        // Depositing after the victim buys a share of the fee at its value,
+        // so round trip gains nothing.
+        let baseline = liq_pool
+            .sandwich_profit(0, 1000 * UNIT, 10000 * UNIT)
+            .unwrap();
+        assert!(baseline <= 0 && baseline > -10);
+
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let baseline = liq_pool
+            .sandwich_profit(0, 4515 * UNIT, 10000 * UNIT)
+            .unwrap();
+        assert!(baseline <= 0 && baseline > -10);
+        // Front-running below target raises victim's fee, but attacker
+        // pays even higher fee for it and does not get it back.
+        let profit = liq_pool
+            .sandwich_profit(4515 * UNIT, 4515 * UNIT, 10000 * UNIT)
+            .unwrap();
+        assert!(profit < baseline);
+        let front_loss = liq_pool.swap_cost_summary(4515 * UNIT).unwrap();
+        assert!(profit < -(front_loss.fee_token as i128));
+        assert!(liq_pool.sandwich_profit(0, 0, 10000 * UNIT).is_err());
+    }
+
+    #[test]
+    fn test_single_sided_deposit_penalty() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(600 * UNIT).unwrap();
+        liq_pool.swap(100 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.single_sided_deposit_penalty(300 * UNIT).unwrap(),
+            0
+        );
+        assert!(liq_pool.single_sided_deposit_penalty(0).is_err());
+    }
+
+    #[test]
+    fn test_swap_cost_summary() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        assert_eq!(
+            liq_pool.swap_cost_summary(4515 * UNIT).unwrap(),
+            SwapCostSummary {
+                fair_value: 9030 * UNIT,
+                token_out: 8980967100000,
+                fee_token: 49032900000,
+                rounding_loss: 0,
+                effective_rate: 198914 * UNIT / 100000,
+                fee_bps: 54,
+            }
+        );
+
+        let mut liq_pool = LiqPool::new(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            19 * UNIT / 10,
+        )
+        .unwrap();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        for st_token_amount in [1, 7, 1001, 45 * UNIT + 3, 300000 * UNIT + 1] {
+            let summary = liq_pool.swap_cost_summary(st_token_amount).unwrap();
+            let collected_fees = liq_pool.collected_fees();
+            assert_eq!(liq_pool.swap(st_token_amount).unwrap(), summary.token_out);
+            assert_eq!(
+                summary.fair_value - summary.token_out,
+                summary.fee_token + summary.rounding_loss
+            );
+            assert_eq!(
+                liq_pool.collected_fees() - collected_fees,
+                summary.fee_token + summary.rounding_loss
+            );
+            assert!(summary.rounding_loss <= 1);
+        }
+        // Whole fee of the smallest swap comes from rounding.
+        let summary = liq_pool.swap_cost_summary(1).unwrap();
+        assert_eq!(summary.fair_value, 1);
+        assert_eq!(summary.token_out, 0);
+        assert_eq!(summary.fee_token, 0);
+        assert_eq!(summary.rounding_loss, 1);
+    }
+
+    #[test]
+    fn test_quote_swap() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let quote = liq_pool.quote_swap(4515 * UNIT).unwrap();
+        assert_eq!(quote, (8980967100000, 543 * UNIT / 100000));
+        assert_eq!(liq_pool.token, 100030 * UNIT);
+        assert_eq!(liq_pool.st_token, 0);
+        assert_eq!(liq_pool.lp_token_supply, 100030 * UNIT);
+        assert_eq!(liq_pool.swap(4515 * UNIT).unwrap(), quote.0);
+    }
+
+    #[test]
+    fn test_linear_fee_with_zero_target() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.liq_target = 0;
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        assert_eq!(liq_pool.linear_fee(50 * UNIT).unwrap(), liq_pool.min_fee);
+        assert_eq!(liq_pool.linear_fee(100 * UNIT).unwrap(), liq_pool.min_fee);
+        assert_eq!(liq_pool.linear_fee(200 * UNIT).unwrap(), liq_pool.min_fee);
+        assert_eq!(liq_pool.swap(25 * UNIT).unwrap(), 4985 * UNIT / 100);
+    }
+
+    fn generic_swap_exact_out_test(init_liq: u64, desired_token_out: u64) {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(init_liq).unwrap();
+        let mut forward_pool = liq_pool.clone();
+        let st_token_amount = liq_pool.swap_exact_out(desired_token_out).unwrap();
+        let token_amount = forward_pool.clone().swap(st_token_amount - 1).unwrap();
+        assert!(token_amount < desired_token_out);
+        let token_amount = forward_pool.swap(st_token_amount).unwrap();
+        assert!(token_amount >= desired_token_out);
+        assert_eq!(liq_pool.token, forward_pool.token);
+        assert_eq!(liq_pool.st_token, forward_pool.st_token);
+        assert_eq!(liq_pool.token, init_liq - token_amount);
+    }
+
+    #[test]
+    fn test_swap_exact_out_with_target_reached() {
+        generic_swap_exact_out_test(581250 * UNIT, 8973 * UNIT / 100);
+    }
+
+    #[test]
+    fn test_swap_exact_out_with_target_not_reached() {
+        generic_swap_exact_out_test(100030 * UNIT, 8980967100000);
+        generic_swap_exact_out_test(100030 * UNIT, 50000 * UNIT);
+    }
+
+    #[test]
+    fn test_swap_exact_out_draining_pool() {
+        generic_swap_exact_out_test(100 * UNIT, 99 * UNIT);
+    }
+
+    #[test]
+    fn test_swap_exact_out_with_large_reserves() {
+        for (token, out) in [
+            (10000000000 * UNIT, 10000000000 * UNIT / 3),
+            (18000000000 * UNIT, 18000000000 * UNIT / 3),
+        ] {
+            let mut liq_pool =
+                LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, token, 2 * UNIT).unwrap();
+            liq_pool.add_liquidity(token).unwrap();
+            let forward_pool = liq_pool.clone();
+            let st_token_amount = liq_pool.swap_exact_out(out).unwrap();
+            assert!(forward_pool.quote_swap(st_token_amount).unwrap().0 >= out);
+            assert!(forward_pool.quote_swap(st_token_amount - 1).unwrap().0 < out);
+            let token_amount = forward_pool.quote_swap(st_token_amount).unwrap().0;
+            assert_eq!(liq_pool.token, token - token_amount);
+        }
+    }
+
+    #[test]
+    fn test_swapping_with_higher_price() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        let mut cheaper_pool = liq_pool.clone();
+        cheaper_pool.update_price(UNIT).unwrap();
+        assert_eq!(cheaper_pool.swap(45 * UNIT).unwrap(), 448650 * UNIT / 10000);
+        assert_eq!(liq_pool.swap(45 * UNIT).unwrap(), 8973 * UNIT / 100);
+    }
+
+    #[test]
+    fn test_adding_liquidity_values_st_token_at_price() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.token = 500 * UNIT;
+        liq_pool.st_token = 250 * UNIT;
+        liq_pool.lp_token_supply = 1000 * UNIT;
+        // Pool is worth 1000 token at price 2.
+        assert_eq!(liq_pool.add_liquidity(100 * UNIT).unwrap(), 100 * UNIT);
+        liq_pool.update_price(6 * UNIT).unwrap();
+        // Now it is worth 2100 token for 1100 lp tokens.
+        assert_eq!(liq_pool.add_liquidity(210 * UNIT).unwrap(), 110 * UNIT);
+    }
+
+    #[test]
+    fn test_swap_with_min_out() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let token_amount = liq_pool
+            .clone()
+            .swap_with_min_out(4515 * UNIT, 8980967100000)
+            .unwrap();
+        assert_eq!(token_amount, 8980967100000);
+    }
+
+    #[test]
+    fn test_collected_fees() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100200 * UNIT).unwrap();
+        let mut expected_fees = 0;
+        for st_token_amount in [45 * UNIT, 4515 * UNIT, 10000 * UNIT] {
+            let token_amount = value(st_token_amount, liq_pool.price).unwrap();
+            let out_token_amount = liq_pool.swap(st_token_amount).unwrap();
+            expected_fees += token_amount - out_token_amount;
+        }
+        assert_eq!(liq_pool.collected_fees(), expected_fees);
+        assert_eq!(
+            liq_pool.total_value(),
+            100200 * UNIT + liq_pool.collected_fees()
+        );
+    }
+
+    #[test]
+    fn test_withdraw_collected_fees() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100200 * UNIT).unwrap();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        let fees = liq_pool.collected_fees();
+        let token = liq_pool.token;
+        assert_eq!(liq_pool.withdrawable_fees().unwrap(), fees);
+        assert_eq!(
+            liq_pool.withdraw_collected_fees(fees / 2).unwrap(),
+            fees / 2
+        );
+        assert_eq!(liq_pool.collected_fees(), fees - fees / 2);
+        assert_eq!(liq_pool.token, token - fees / 2);
+
+        assert!(matches!(
+            liq_pool.withdraw_collected_fees(fees),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert_eq!(liq_pool.collected_fees(), fees - fees / 2);
+
+        liq_pool.withdraw_collected_fees(fees - fees / 2).unwrap();
+        assert_eq!(liq_pool.collected_fees(), 0);
+        assert_eq!(liq_pool.token, token - fees);
+        // Lp tokens are left with exactly the deposit.
+        assert_eq!(liq_pool.total_value(), 100200 * UNIT);
+    }
+
+    #[test]
+    fn test_withdraw_collected_fees_after_lp_exit() {
+        let mut liq_pool = get_example_lp();
+        let lp_token_amount = liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        let fees = liq_pool.collected_fees();
+        assert_eq!(fees, 49032900000);
+        // Lp token holder takes the fees out with the whole pool.
+        liq_pool.remove_all_liquidity(lp_token_amount).unwrap();
+        let lp_token_amount = liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        assert_eq!(liq_pool.withdrawable_fees().unwrap(), 0);
+        assert!(matches!(
+            liq_pool.withdraw_collected_fees(fees),
+            Err(LiqPoolError::InsufficientLiquidity { .. })
+        ));
+        assert_eq!(
+            liq_pool.lp_to_underlying(lp_token_amount).unwrap(),
+            (1000 * UNIT, 0)
+        );
+    }
+
+    #[test]
+    fn test_withdraw_collected_fees_after_partial_exit() {
+        let mut liq_pool = get_example_lp();
+        let lp_token_amount = liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        let fees = liq_pool.collected_fees();
+        // A quarter of lp tokens takes a quarter of fees with it.
+        liq_pool.remove_liquidity(lp_token_amount / 4).unwrap();
+        assert_eq!(liq_pool.lp_principal(), 100030 * UNIT * 3 / 4);
+        let withdrawable = liq_pool.withdrawable_fees().unwrap();
+        assert!(withdrawable <= fees * 3 / 4 + 1 && withdrawable + 1 >= fees * 3 / 4);
+        liq_pool.withdraw_collected_fees(withdrawable).unwrap();
+        assert!(liq_pool.total_value() >= liq_pool.lp_principal());
+        assert!(liq_pool.withdraw_collected_fees(1).is_err());
+    }
+
+    #[test]
+    fn test_withdraw_collected_fees_above_token_reserve() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        liq_pool.swap(510 * UNIT).unwrap();
+        assert!(liq_pool.collected_fees() > liq_pool.token);
+        assert_eq!(liq_pool.withdrawable_fees().unwrap(), liq_pool.token);
+        assert!(matches!(
+            liq_pool.withdraw_collected_fees(liq_pool.collected_fees()),
+            Err(LiqPoolError::InsufficientLiquidity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_small_swaps_do_not_lose_fee_fractions() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        // Exact fee in lamports scaled by UNIT.
+        let mut exact_fees: u128 = 0;
+        for _ in 0..1000 {
+            let st_token_amount = 12345;
+            let token_amount = value(st_token_amount, liq_pool.price).unwrap();
+            let fee = liq_pool.current_fee(st_token_amount).unwrap();
+            exact_fees += token_amount as u128 * fee as u128;
+            liq_pool.swap(st_token_amount).unwrap();
+        }
+        let collected_fees = liq_pool.collected_fees() as u128 * UNIT as u128;
+        assert!(collected_fees >= exact_fees);
+        // Each swap pays at most one lamport over its exact fee.
+        assert!(collected_fees - exact_fees < 1000 * UNIT as u128);
+    }
+
+    #[test]
+    fn test_swap_statistics() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100200 * UNIT).unwrap();
+        for st_token_amount in [45 * UNIT, 4515 * UNIT, 10000 * UNIT] {
+            liq_pool.swap(st_token_amount).unwrap();
+        }
+        // failed swaps are not counted
+        assert!(liq_pool.swap(100000 * UNIT).is_err());
+        assert_eq!(liq_pool.swap_count(), 3);
+        assert_eq!(liq_pool.total_volume(), 14560 * UNIT);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct AlwaysMaxFee;
+
+    impl FeeCurve for AlwaysMaxFee {
+        fn fee(&self, _: u64, _: u64, _: u64, max_fee: u64) -> Result<u64> {
+            Ok(max_fee)
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct HalfTargetStep;
+
+    impl FeeCurve for HalfTargetStep {
+        fn fee(&self, liq_after: u64, liq_target: u64, min_fee: u64, max_fee: u64) -> Result<u64> {
+            Ok(if liq_after >= liq_target / 2 {
+                min_fee
+            } else {
+                max_fee
+            })
+        }
+    }
+
+    #[test]
+    fn test_swaps_until_max_fee_with_custom_fee_curve() {
+        let mut liq_pool = LiqPool::with_fee_curve(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            HalfTargetStep,
+        )
+        .unwrap();
+        liq_pool.add_liquidity(101000 * UNIT).unwrap();
+        // Each swap takes 199.4 token, fee stays at min_fee while at
+        // least 50200 token is left before the swap.
+        let count = liq_pool.swaps_until_max_fee(100 * UNIT).unwrap();
+        assert_eq!(count, 255);
+        for _ in 0..count {
+            assert_eq!(liq_pool.linear_fee(200 * UNIT).unwrap(), liq_pool.min_fee);
+            liq_pool.swap(100 * UNIT).unwrap();
+        }
+        assert_eq!(liq_pool.linear_fee(200 * UNIT).unwrap(), liq_pool.max_fee);
+
+        let mut liq_pool = LiqPool::with_fee_curve(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            AlwaysMaxFee,
+        )
+        .unwrap();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        assert_eq!(liq_pool.swaps_until_max_fee(100 * UNIT).unwrap(), 0);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct AboveHundredPercentFee;
+
+    impl FeeCurve for AboveHundredPercentFee {
+        fn fee(&self, _: u64, _: u64, _: u64, _: u64) -> Result<u64> {
+            Ok(2 * UNIT)
+        }
+    }
+
+    #[test]
+    fn test_custom_fee_curve_above_hundred_percent() {
+        let mut liq_pool = LiqPool::with_fee_curve(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            AboveHundredPercentFee,
+        )
+        .unwrap();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        for result in [
+            liq_pool.quote_swap(45 * UNIT).map(|(out, _)| out),
+            liq_pool.rounding_loss_on_swap(45 * UNIT),
+            liq_pool.marginal_rate(),
+        ] {
+            assert!(matches!(result, Err(LiqPoolError::InvalidInputData(_))));
+        }
+        assert!(parity_swap(&liq_pool, &get_example_lp()).is_err());
+        assert!(liq_pool.swap(45 * UNIT).is_err());
+        assert_eq!(liq_pool.token, 581250 * UNIT);
+    }
+
+    #[test]
+    fn test_swapping_with_custom_fee_curve() {
+        let mut liq_pool = LiqPool::with_fee_curve(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            AlwaysMaxFee,
+        )
+        .unwrap();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        // 90 - 3%
+        assert_eq!(liq_pool.swap(45 * UNIT).unwrap(), 873 * UNIT / 10);
+    }
+
+    #[test]
+    fn test_swap_fee_rounding_favors_pool() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(581250 * UNIT).unwrap();
+        // 0.3% of 2002 is 6.006, pool keeps 7.
+        assert_eq!(liq_pool.swap(1001).unwrap(), 1995);
+        assert_eq!(liq_pool.collected_fees(), 7);
+    }
+
+    #[test]
+    fn test_max_swappable() {
+        for init_liq in [100 * UNIT, 100030 * UNIT, 581250 * UNIT] {
+            let mut liq_pool = get_example_lp();
+            liq_pool.add_liquidity(init_liq).unwrap();
+            let st_token_amount = liq_pool.max_swappable().unwrap();
+            assert!(matches!(
+                liq_pool.clone().swap(st_token_amount + 1),
+                Err(LiqPoolError::InsufficientLiquidity { .. })
+            ));
+            liq_pool.swap(st_token_amount).unwrap();
+        }
+        assert!(matches!(
+            get_example_lp().max_swappable(),
+            Err(LiqPoolError::PoolNotInitialized)
+        ));
+    }
+
+    #[test]
+    fn test_swap_partial() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let mut full_swap_pool = liq_pool.clone();
+        let partial = liq_pool.swap_partial(4515 * UNIT).unwrap();
+        assert_eq!(
+            partial,
+            PartialSwap {
+                st_token_consumed: 4515 * UNIT,
+                token_out: full_swap_pool.swap(4515 * UNIT).unwrap(),
+                st_token_remaining: 0,
+            }
+        );
+        assert_eq!(liq_pool, full_swap_pool);
+
+        // 1000 st_token are worth twice the token reserve
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        let max_swappable = liq_pool.max_swappable().unwrap();
+        let partial = liq_pool.swap_partial(1000 * UNIT).unwrap();
+        assert_eq!(partial.st_token_consumed, max_swappable);
+        assert_eq!(partial.st_token_remaining, 1000 * UNIT - max_swappable);
+        assert_eq!(liq_pool.token, 1000 * UNIT - partial.token_out);
+        assert_eq!(liq_pool.max_swappable().unwrap(), 0);
+        assert_eq!(
+            liq_pool.swap_partial(10 * UNIT).unwrap(),
+            PartialSwap {
+                st_token_consumed: 0,
+                token_out: 0,
+                st_token_remaining: 10 * UNIT,
+            }
+        );
+    }
+
+    #[test]
+    fn test_saturating_swap() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        assert_eq!(liq_pool.saturating_swap(4515 * UNIT), 8980967100000);
+
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        let max_out = liq_pool
+            .quote_swap(liq_pool.max_swappable().unwrap())
+            .unwrap()
+            .0;
+        assert_eq!(liq_pool.saturating_swap(1000 * UNIT), max_out);
+        assert_eq!(liq_pool.saturating_swap(1000 * UNIT), 0);
+
+        assert_eq!(get_example_lp().saturating_swap(10 * UNIT), 0);
+    }
+
     /* Test error handling */
 
     #[test]
@@ -194,17 +4221,293 @@ mod tests {
         assert!(liq_pool.remove_liquidity(100).is_err());
     }
 
+    #[test]
+    fn test_pool_not_initialized() {
+        let mut liq_pool = get_example_lp();
+        assert!(matches!(
+            liq_pool.swap(100),
+            Err(LiqPoolError::PoolNotInitialized)
+        ));
+        assert!(matches!(
+            liq_pool.remove_liquidity(1),
+            Err(LiqPoolError::PoolNotInitialized)
+        ));
+        assert!(matches!(
+            liq_pool.add_liquidity_st(100),
+            Err(LiqPoolError::PoolNotInitialized)
+        ));
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        liq_pool.swap(10 * UNIT).unwrap();
+        liq_pool.add_liquidity_st(10 * UNIT).unwrap();
+        liq_pool.remove_liquidity(10 * UNIT).unwrap();
+    }
+
+    #[test]
+    fn test_remove_all_liquidity() {
+        let mut liq_pool = get_example_lp();
+        let alice_lp = liq_pool.add_liquidity(800 * UNIT).unwrap();
+        liq_pool.swap(150 * UNIT).unwrap();
+        let bob_lp = liq_pool.add_liquidity(333 * UNIT).unwrap();
+        liq_pool.swap(33 * UNIT).unwrap();
+        assert!(matches!(
+            liq_pool.remove_all_liquidity(alice_lp + bob_lp + 1),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        liq_pool.remove_all_liquidity(bob_lp).unwrap();
+        let (token, st_token) = (liq_pool.token, liq_pool.st_token);
+        assert_eq!(
+            liq_pool.remove_all_liquidity(alice_lp).unwrap(),
+            (token, st_token)
+        );
+        assert_eq!(
+            (liq_pool.token, liq_pool.st_token, liq_pool.lp_token_supply),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_lp_to_underlying() {
+        let mut liq_pool = get_example_lp();
+        assert_eq!(liq_pool.lp_to_underlying(0).unwrap(), (0, 0));
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        liq_pool.swap(4515 * UNIT).unwrap();
+        let underlying = liq_pool.lp_to_underlying(30000 * UNIT).unwrap();
+        assert_eq!(
+            underlying,
+            liq_pool.clone().remove_liquidity(30000 * UNIT).unwrap()
+        );
+        assert!(matches!(
+            liq_pool.lp_to_underlying(100030 * UNIT + 1),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_removing_dust_liquidity() {
+        // every lp token is worth 0.1 of the smallest unit of token
+        let mut liq_pool = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            2 * UNIT,
+            100000 * UNIT,
+            0,
+            1000000 * UNIT,
+        )
+        .unwrap();
+        assert!(matches!(
+            liq_pool.remove_liquidity(9),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert_eq!(liq_pool.lp_token_supply, 1000000 * UNIT);
+        assert_eq!(liq_pool.remove_liquidity(10).unwrap(), (1, 0));
+    }
+
+    #[test]
+    fn test_swap_overflowing_st_token_reserve() {
+        let mut liq_pool = LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            UNIT / 1000,
+            1000000 * UNIT,
+            u64::MAX - 10 * UNIT,
+            1000000 * UNIT,
+        )
+        .unwrap();
+        let before = liq_pool.clone();
+        assert!(matches!(
+            liq_pool.swap(20 * UNIT),
+            Err(LiqPoolError::Overflow)
+        ));
+        assert!(matches!(
+            liq_pool.quote_swap(20 * UNIT),
+            Err(LiqPoolError::Overflow)
+        ));
+        assert_eq!(liq_pool, before);
+        liq_pool.swap(10 * UNIT).unwrap();
+        assert_eq!(liq_pool.st_token, u64::MAX);
+    }
+
+    #[test]
+    fn test_zero_amounts() {
+        let mut liq_pool = get_example_lp();
+        assert!(matches!(
+            liq_pool.add_liquidity(0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        let before = liq_pool.clone();
+        assert!(matches!(
+            liq_pool.remove_liquidity(0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(matches!(
+            liq_pool.swap(0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(matches!(
+            liq_pool.add_liquidity_st(0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert_eq!(liq_pool, before);
+    }
+
     #[test]
     fn test_unstaking_too_much() {
         let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100).unwrap();
         assert!(liq_pool.swap(100).is_err());
     }
 
+    #[test]
+    fn test_quoting_too_much() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100).unwrap();
+        assert!(matches!(
+            liq_pool.quote_swap(100),
+            Err(LiqPoolError::InsufficientLiquidity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_insufficient_liquidity_shortfall() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        // 60 st_token are worth 120 token, 3% fee leaves 116.4
+        assert!(matches!(
+            liq_pool.swap(60 * UNIT),
+            Err(LiqPoolError::InsufficientLiquidity {
+                requested: 116400000000,
+                available: 100000000000,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_new_with_max_fee_smaller_than_min_fee() {
+        assert!(matches!(
+            LiqPool::new(3 * UNIT / 1000, 3 * UNIT / 100, 100000 * UNIT, 2 * UNIT),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_new_with_max_fee_over_100_percent() {
+        assert!(LiqPool::new(UNIT, 0, 100000 * UNIT, 2 * UNIT).is_ok());
+        assert!(matches!(
+            LiqPool::new(UNIT + 1, 0, 100000 * UNIT, 2 * UNIT),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_zero_price_is_rejected() {
+        assert!(matches!(
+            LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 100000 * UNIT, 0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(LiqPool::from_state(
+            3 * UNIT / 100,
+            3 * UNIT / 1000,
+            100000 * UNIT,
+            0,
+            0,
+            100 * UNIT,
+            100 * UNIT
+        )
+        .is_err());
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        assert!(matches!(
+            liq_pool.update_price(0),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+        assert!(liq_pool.advance_epoch(&[UNIT], 0).is_err());
+        assert_eq!(liq_pool.price(), 2 * UNIT);
+        assert_eq!(liq_pool.st_token, 0);
+    }
+
+    #[test]
+    fn test_new_with_zero_liq_target() {
+        assert!(matches!(
+            LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 0, 2 * UNIT),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_swap_exact_out_with_fee_of_100_percent() {
+        let mut liq_pool = LiqPool::new(UNIT, UNIT, 100 * UNIT, 2 * UNIT).unwrap();
+        liq_pool.add_liquidity(1000 * UNIT).unwrap();
+        assert!(matches!(
+            liq_pool.swap_exact_out(UNIT),
+            Err(LiqPoolError::InvalidInputData(_))
+        ));
+    }
+
+    #[test]
+    fn test_swap_exact_out_too_much() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100 * UNIT).unwrap();
+        assert!(matches!(
+            liq_pool.swap_exact_out(100 * UNIT + 1),
+            Err(LiqPoolError::InsufficientLiquidity { .. })
+        ));
+    }
+
+    #[test]
+    fn test_swap_with_min_out_exceeded() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(100030 * UNIT).unwrap();
+        let checkpoint = liq_pool.clone();
+        assert!(matches!(
+            liq_pool.swap_with_min_out(4515 * UNIT, 8980967100001),
+            Err(LiqPoolError::SlippageExceeded {
+                expected: 8980967100001,
+                actual: 8980967100000
+            })
+        ));
+        assert_eq!(liq_pool, checkpoint);
+    }
+
+    #[test]
+    fn test_removing_liquidity_by_invalid_fraction() {
+        let mut liq_pool = get_example_lp();
+        liq_pool.add_liquidity(500 * UNIT).unwrap();
+        assert!(liq_pool
+            .remove_liquidity_by_fraction(500 * UNIT, 2, 1)
+            .is_err());
+        assert!(liq_pool
+            .remove_liquidity_by_fraction(500 * UNIT, 0, 0)
+            .is_err());
+    }
+
     /* Test complex scenerios */
 
+    #[test]
+    fn test_swap_detailed() {
+        let mut liq_pool =
+            LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 500 * UNIT, 2 * UNIT).unwrap();
+        liq_pool.add_liquidity(800 * UNIT).unwrap();
+        let receipt = liq_pool.swap_detailed(150 * UNIT).unwrap();
+        assert_eq!(receipt.fee_rate, 3 * UNIT / 1000);
+        // Same swap as Carlos does in complex scenario, fee is 1.91514%.
+        let receipt = liq_pool.swap_detailed(150 * UNIT).unwrap();
+        assert_eq!(
+            receipt,
+            SwapReceipt {
+                token_out: 29425458 * UNIT / 100000,
+                fee_rate: 191514 * UNIT / 10000000,
+                fee_amount: 574542 * UNIT / 100000,
+            }
+        );
+    }
+
     #[test]
     fn test_complex_scenerio() {
-        let mut liq_pool = LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 500 * UNIT, 2 * UNIT);
+        let mut liq_pool =
+            LiqPool::new(3 * UNIT / 100, 3 * UNIT / 1000, 500 * UNIT, 2 * UNIT).unwrap();
         // Alice puts 800 token in liq pool.
         liq_pool.add_liquidity(800 * UNIT).unwrap();
         assert_eq!(liq_pool.token, 800 * UNIT);
@@ -0,0 +1,95 @@
+//! Randomized checks of invariants of the pool.
+//!
+//! Pools are reconstructed with `LiqPool::from_state`, which accepts any
+//! consistent reserves, so states reachable only after long history are
+//! covered as well.
+
+use liquidity_pool::calc::{value, UNIT};
+use liquidity_pool::error::LiqPoolError;
+use liquidity_pool::LiqPool;
+use proptest::prelude::*;
+
+prop_compose! {
+    fn arb_pool()(
+        max_fee in 0..UNIT / 10,
+        min_fee_ratio in 0..=100u64,
+        liq_target in 1..1_000_000 * UNIT,
+        price in UNIT..3 * UNIT,
+        token in 1..10_000_000 * UNIT,
+        st_token in 0..10_000_000 * UNIT,
+        // lp token price between 0.5 and 2
+        lp_price_permille in 500..=2000u64,
+    ) -> LiqPool {
+        let total_value = token + value(st_token, price).unwrap();
+        let lp_token_supply = (total_value as u128 * 1000 / lp_price_permille as u128) as u64;
+        LiqPool::from_state(
+            max_fee,
+            max_fee * min_fee_ratio / 100,
+            liq_target,
+            price,
+            token,
+            st_token,
+            lp_token_supply.max(1),
+        )
+        .unwrap()
+    }
+}
+
+proptest! {
+    // Swaps only add fee to total value while lp supply stays the same. Fee
+    // is rounded up and st_token is valued as a whole, so the lp token price
+    // never drops, not even by rounding.
+    #[test]
+    fn lp_token_price_never_decreases_on_swaps(
+        mut liq_pool in arb_pool(),
+        swaps in prop::collection::vec(1..200_000 * UNIT, 1..20),
+    ) {
+        let mut lp_token_price = liq_pool.lp_token_price().unwrap();
+        for st_token_amount in swaps {
+            let before = liq_pool.clone();
+            match liq_pool.swap(st_token_amount) {
+                Ok(token_out) => {
+                    prop_assert_eq!(before.token_reserve() - liq_pool.token_reserve(), token_out);
+                    prop_assert!(token_out <= value(st_token_amount, liq_pool.price()).unwrap());
+                }
+                Err(LiqPoolError::InsufficientLiquidity { .. }) => prop_assert_eq!(&liq_pool, &before),
+                Err(err) => return Err(TestCaseError::fail(err.to_string())),
+            }
+            let new_lp_token_price = liq_pool.lp_token_price().unwrap();
+            prop_assert!(new_lp_token_price >= lp_token_price);
+            lp_token_price = new_lp_token_price;
+        }
+    }
+
+    // Minting and burning lp tokens both round down, in favor of the pool.
+    // Depositor loses less than value of one lp token to minting, less than
+    // one token and value of one st_token to burning, and less than one
+    // token to valuing st_token part of the withdrawal. Dust deposit may
+    // get lp tokens paying out nothing, which cannot be removed, and is
+    // lost whole within the same tolerance.
+    #[test]
+    fn add_then_remove_returns_deposit(
+        mut liq_pool in arb_pool(),
+        deposit in prop_oneof![0..10u64, 0..1_000_000 * UNIT],
+    ) {
+        let lp_token_price = liq_pool.lp_token_price().unwrap();
+        let lp_token_amount = match liq_pool.add_liquidity(deposit) {
+            Ok(lp_token_amount) => lp_token_amount,
+            Err(LiqPoolError::InvalidInputData(_)) => {
+                prop_assert_eq!(deposit, 0);
+                return Ok(());
+            }
+            Err(err) => return Err(TestCaseError::fail(err.to_string())),
+        };
+        let withdrawn = match liq_pool.remove_liquidity(lp_token_amount) {
+            Ok((token_out, st_token_out)) => {
+                token_out + value(st_token_out, liq_pool.price()).unwrap()
+            }
+            Err(LiqPoolError::InvalidInputData(_)) => 0,
+            Err(err) => return Err(TestCaseError::fail(err.to_string())),
+        };
+        let tolerance = lp_token_price.div_ceil(UNIT) + liq_pool.price().div_ceil(UNIT) + 2;
+        prop_assert!(withdrawn <= deposit);
+        prop_assert!(withdrawn + tolerance >= deposit);
+    }
+}